@@ -1,3 +1,5 @@
+import json
+import os
 import pandas as pd
 import matplotlib.pyplot as plt
 import numpy as np
@@ -14,6 +16,41 @@ def parse_args():
     return parser.parse_args()
 
 
+def read_sidecar(path):
+    """Returns the JSON sidecar NiGUI writes next to each recording, or an
+    empty dict if there is none."""
+    sidecar = path + ".json"
+    if not os.path.exists(sidecar):
+        return {}
+    with open(sidecar) as f:
+        return json.load(f)
+
+
+def read_channel(path, ch):
+    """Returns the samples of a channel and the sampling rate of a recording,
+    either a NiGUI CSV or an .npz file."""
+    if path.endswith(".npz"):
+        npz = np.load(path)
+        return npz["data"][:, ch], float(npz["sampling_rate"])
+
+    fs = read_sidecar(path).get("sampling_rate", 250)
+    df = pd.read_csv(path)
+    return df[f"channel-{ch}"].values, fs
+
+
+def good_samples(path, n, fs):
+    """Returns a boolean mask of the `n` samples of a recording that are not
+    inside a bad segment, i.e. an annotation whose label starts with BAD."""
+    mask = np.ones(n, dtype=bool)
+    for annot in read_sidecar(path).get("annotations", []):
+        if not annot["label"].upper().startswith("BAD"):
+            continue
+        start = int(round(annot["onset"] * fs))
+        end = int(round((annot["onset"] + annot["duration"]) * fs))
+        mask[start:max(end, start + 1)] = False
+    return mask
+
+
 def moving_average(a, n=30):
     ret = np.cumsum(a, dtype=float)
     ret[n:] = ret[n:] - ret[:-n]
@@ -26,10 +63,10 @@ if __name__ == "__main__":
     ch = args.channel
     min_freq, max_freq = args.min_freq, args.max_freq
 
-    df = pd.read_csv(fname)
-    xx = df[f"channel-{ch}"].values
+    xx, fs = read_channel(fname, ch)
+    xx = xx[good_samples(fname, len(xx), fs)]
 
-    spectrum, freqs, _ = plt.magnitude_spectrum(xx, Fs=250)
+    spectrum, freqs, _ = plt.magnitude_spectrum(xx, Fs=fs)
     plt.clf()
 
 
@@ -1,5 +1,6 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")] // hide console window on Windows in release
 
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
 use chrono::prelude::*;
@@ -10,17 +11,19 @@ use egui::ColorImage;
 use rfd::FileDialog;
 
 use super::wave;
-use super::wifi::{send_tcp_command, NAPSE_ADDR};
+use super::wifi::{send_tcp_command, CMD_MARK, CMD_MODE_OFF, CMD_NOISE_ON, CMD_TEST_ON, NAPSE_ADDR};
 use crate::log_err;
-use crate::wave::WAVE_BUFFS_NUM;
-use crate::wifi::{ERRORS, MARKER_ADDR, NOTIFICATIONS};
+use crate::viewer::Viewer;
+use crate::record::{self, bids::Participant, csv::CsvOptions, naming, stream::{Recorder, Split, Unfinished}, RecordingInfo};
+use crate::wifi::{ERRORS, MARKER_ADDR};
 use json::JsonValue;
 use egui_notify::Toasts;
 
-pub const PLUGINS_CFG_PATH: &'static str = "plugins.json";
+pub const PLUGINS_CFG_PATH: &str = "plugins.json";
 
 pub struct MyApp {
-    recording: bool,
+    recorder: Option<Recorder>,
+    unfinished: Option<Unfinished>,
     mark_str: String,
     add_str: String,
     test_mode: bool,
@@ -33,6 +36,29 @@ pub struct MyApp {
     selected_plugin: Option<String>,
     plugin_flags: Vec<String>,
     plugin_args: Vec<String>,
+    session_open: bool,
+    settings_open: bool,
+    viewer_open: bool,
+    viewer: Viewer,
+    csv_options: CsvOptions,
+    /// Recordings are saved here without asking, if set
+    output_dir: Option<PathBuf>,
+    name_template: String,
+    /// Extension of the recordings, see `naming::FORMATS`
+    output_ext: &'static str,
+    /// Duration of timed recordings, in `record_unit`s
+    record_for: Option<f64>,
+    /// Seconds per unit of `record_for`: 1 or 60
+    record_unit: f64,
+    split: Split,
+    live_view: wave::LiveView,
+    subject: String,
+    session: String,
+    task: String,
+    participant: Participant,
+    notes: String,
+    /// Labels of the marks 1-6 (the ones sent with QWERTY)
+    marker_labels: Vec<String>,
 
     logo_tex: Option<egui::TextureHandle>,
 }
@@ -40,7 +66,8 @@ pub struct MyApp {
 impl Default for MyApp {
     fn default() -> Self {
         Self {
-            recording: false,
+            recorder: None,
+            unfinished: None,
             mark_str: String::from("1"),
             add_str: String::from("172.16.30.150"),
             test_mode: false,
@@ -54,6 +81,24 @@ impl Default for MyApp {
             selected_plugin: None,
             plugin_flags: vec![],
             plugin_args: vec![],
+            session_open: false,
+            settings_open: false,
+            viewer_open: false,
+            viewer: Viewer::default(),
+            csv_options: CsvOptions::default(),
+            output_dir: None,
+            name_template: naming::DEFAULT_TEMPLATE.into(),
+            output_ext: "csv",
+            record_for: None,
+            record_unit: 1.0,
+            split: Split::default(),
+            live_view: wave::LiveView::default(),
+            subject: String::new(),
+            session: String::new(),
+            task: String::new(),
+            participant: Participant::default(),
+            notes: String::new(),
+            marker_labels: vec![String::new(); 6],
         }
     }
 }
@@ -77,7 +122,7 @@ impl MyApp {
                     // check if the current plugin is the one selected
                     let is_selected = self.selected_plugin
                         .as_ref()
-                        .map_or(false, |v| v == plugin_name.as_str());
+                        .is_some_and(|v| v == plugin_name.as_str());
 
                     ui.horizontal(|ui|  {
                         let mut button = egui::Button::new(RichText::new(&plugin_name).size(14.0).strong());
@@ -119,30 +164,44 @@ impl MyApp {
 
                                         ui.text_edit_singleline(&mut self.plugin_args[i]);
 
-                                        // if the argument value is of type $path, then add a button to select files
-                                        if arg_default == "$path" {
-                                            if ui.button("open file").clicked() {
-                                                let file = FileDialog::new()
-                                                    .pick_file()
-                                                    .map(|s| s.to_string_lossy().to_string())
-                                                    .unwrap_or("".to_string());
-                                                self.plugin_args[i] = file;
-                                            }
+                                        // if the argument value is of type $path or $npz, then add a button to select files
+                                        if (arg_default == "$path" || arg_default == "$npz") && ui.button("open file").clicked() {
+                                            let file = FileDialog::new()
+                                                .pick_file()
+                                                .map(|s| s.to_string_lossy().to_string())
+                                                .unwrap_or("".to_string());
+                                            self.plugin_args[i] = file;
                                         }
                                     });
                                 }
 
                                 let run = Button::new("Run").fill(Color32::DARK_GREEN).small();
                                 if ui.add(run).clicked() {
+                                    // $npz arguments receive the recording converted to .npz
+                                    // and the converted files are removed once the plugin is done
+                                    let mut args = self.plugin_args.clone();
+                                    let mut converted = vec![];
+                                    for (arg, (_, default)) in args.iter_mut().zip(plugin["args"].entries()) {
+                                        if default.as_str() == Some("$npz") && !arg.is_empty() {
+                                            match record::npz::ensure_npz(Path::new(arg.as_str())) {
+                                                Ok(npz) => {
+                                                    *arg = npz.path.to_string_lossy().to_string();
+                                                    converted.push(npz);
+                                                }
+                                                Err(e) => log_err(format!("Failed to convert {} to npz: {}", arg, e)),
+                                            }
+                                        }
+                                    }
                                     let res = crate::plugins::run_script(
                                         cfg["venv"].to_string(),
                                         plugin["path"].to_string(),
                                         &self.plugin_flags,
-                                        &self.plugin_args);
+                                        &args);
                                     match res {
                                         Ok(_) => println!("Plugin terminated correctly"),
                                         Err(err) => eprintln!("Plugin error: {err}"),
                                     }
+                                    drop(converted);
                                 }
                             }
                         }
@@ -156,54 +215,320 @@ impl MyApp {
         });
     }
 
+    fn session_menu(&mut self, ctx: &Context) {
+        egui::Window::new("Session").show(ctx, |ui| {
+            egui::Grid::new("session grid").num_columns(2).show(ui, |ui| {
+                ui.label("Subject ID: ");
+                ui.text_edit_singleline(&mut self.subject);
+                ui.end_row();
+
+                ui.label("Session: ");
+                ui.text_edit_singleline(&mut self.session);
+                ui.end_row();
+
+                ui.label("Task: ");
+                ui.text_edit_singleline(&mut self.task);
+                ui.end_row();
+
+                ui.label("Age: ");
+                ui.text_edit_singleline(&mut self.participant.age);
+                ui.end_row();
+
+                ui.label("Sex: ");
+                ui.text_edit_singleline(&mut self.participant.sex);
+                ui.end_row();
+
+                for (i, label) in self.marker_labels.iter_mut().enumerate() {
+                    ui.label(format!("Mark {} label: ", i + 1));
+                    ui.text_edit_singleline(label);
+                    ui.end_row();
+                }
+            });
+            ui.label("Notes: ");
+            ui.text_edit_multiline(&mut self.notes);
+
+            ui.separator();
+            if ui.button("Export recording to BIDS...").clicked() {
+                self.export_bids();
+            }
+        });
+    }
+
+    /// Asks for a recording and a BIDS dataset folder, and writes the recording
+    /// into the dataset with the subject, session and task of the session form.
+    fn export_bids(&self) {
+        let Some(file) = FileDialog::new().add_filter("CSV", &["csv"]).pick_file() else {
+            return;
+        };
+        let Some(root) = FileDialog::new().set_title("BIDS dataset folder").pick_folder() else {
+            return;
+        };
+
+        let res = record::load(&file).and_then(|mut rec| {
+            for (value, field) in [
+                (&self.subject, &mut rec.info.subject),
+                (&self.session, &mut rec.info.session),
+                (&self.task, &mut rec.info.task),
+            ] {
+                if !value.trim().is_empty() {
+                    *field = value.trim().to_string();
+                }
+            }
+            record::bids::export(&root, &rec, &self.participant)
+        });
+        match res {
+            Ok(path) => println!("Recording exported to {}", path.display()),
+            Err(e) => log_err(format!("BIDS export failed: {}", e)),
+        }
+    }
+
+    fn settings_menu(&mut self, ctx: &Context) {
+        egui::Window::new("Settings").show(ctx, |ui| {
+            ui.label(RichText::new("Recordings").strong());
+            ui.horizontal(|ui| {
+                ui.label("Output directory: ");
+                match &self.output_dir {
+                    Some(dir) => ui.label(dir.display().to_string()),
+                    None => ui.label("ask every time"),
+                };
+                if ui.button("Choose...").clicked() {
+                    if let Some(dir) = FileDialog::new().pick_folder() {
+                        self.output_dir = Some(dir);
+                    }
+                }
+                if self.output_dir.is_some() && ui.button("Clear").clicked() {
+                    self.output_dir = None;
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label("File name: ");
+                ui.text_edit_singleline(&mut self.name_template)
+                    .on_hover_text("Fields: {subject}, {session}, {task}, {date}, {counter}");
+                egui::ComboBox::from_id_source("output format")
+                    .selected_text(format!(".{}", self.output_ext))
+                    .show_ui(ui, |ui| {
+                        for (name, ext) in naming::FORMATS {
+                            ui.selectable_value(&mut self.output_ext, ext, name);
+                        }
+                    });
+            });
+            ui.horizontal(|ui| {
+                let mut timed = self.record_for.is_some();
+                ui.checkbox(&mut timed, "Record for");
+                if timed {
+                    let mut value = self.record_for.unwrap_or(60.0);
+                    ui.add(egui::DragValue::new(&mut value).clamp_range(1.0..=f64::MAX).speed(1.0));
+                    ui.radio_value(&mut self.record_unit, 1.0, "seconds");
+                    ui.radio_value(&mut self.record_unit, 60.0, "minutes");
+                    self.record_for = Some(value);
+                } else {
+                    self.record_for = None;
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label("Split every");
+                for (value, unit, default) in [
+                    (&mut self.split.minutes, "minutes", 60.0),
+                    (&mut self.split.megabytes, "MB", 500.0),
+                ] {
+                    let mut enabled = value.is_some();
+                    ui.checkbox(&mut enabled, "");
+                    if enabled {
+                        let mut v = value.unwrap_or(default);
+                        ui.add(egui::DragValue::new(&mut v).clamp_range(1.0..=f64::MAX).speed(1.0));
+                        *value = Some(v);
+                    } else {
+                        *value = None;
+                    }
+                    ui.label(unit);
+                }
+            })
+            .response
+            .on_hover_text("Long recordings are saved in numbered parts");
+
+            ui.horizontal(|ui| {
+                let recover = ui.button("Recover...")
+                    .on_hover_text("Write a recording that was not stopped from its .journal file");
+                if recover.clicked() {
+                    if let Some(journal) = FileDialog::new().add_filter("Journal", &["journal"]).pick_file() {
+                        // `rest.edf.journal` -> `rest.edf`
+                        let path = journal.with_extension("");
+                        self.unfinished = record::stream::unfinished(&path);
+                        if self.unfinished.is_none() {
+                            log_err(format!("{} is not a valid journal", journal.display()));
+                        }
+                    }
+                }
+            });
+
+            ui.add(Separator::default().spacing(10.));
+            ui.label(RichText::new("CSV recordings").strong());
+            let opts = &mut self.csv_options;
+            ui.horizontal(|ui| {
+                ui.label("Delimiter: ");
+                for (delimiter, name) in [(',', "comma"), (';', "semicolon"), ('\t', "tab")] {
+                    ui.radio_value(&mut opts.delimiter, delimiter, name);
+                }
+            });
+            ui.horizontal(|ui| {
+                let mut full = opts.precision.is_none();
+                ui.checkbox(&mut full, "Full precision");
+                if full {
+                    opts.precision = None;
+                } else {
+                    let mut decimals = opts.precision.unwrap_or(8);
+                    ui.label("Decimals: ");
+                    ui.add(egui::DragValue::new(&mut decimals).clamp_range(1..=12));
+                    opts.precision = Some(decimals);
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label("Columns: ");
+                let cols = &mut opts.columns;
+                ui.checkbox(&mut cols.sample, "sample");
+                ui.checkbox(&mut cols.timestamp, "timestamp");
+                ui.checkbox(&mut cols.elapsed, "elapsed");
+                // the channels are always written, files without them can't be read back
+                ui.add_enabled(false, egui::Checkbox::new(&mut true, "channels"));
+                ui.checkbox(&mut cols.status, "status");
+                ui.checkbox(&mut cols.mark, "mark");
+            });
+
+            ui.add(Separator::default().spacing(10.));
+            ui.label(RichText::new("Frequency bands").strong());
+            wave::bands::edit(ui);
+        });
+    }
+
+    /// Information of a recording started now, with the current settings.
+    fn recording_info(&self) -> RecordingInfo {
+        let mut info = RecordingInfo::new(Local::now());
+        info.subject = self.subject.trim().to_string();
+        info.session = self.session.trim().to_string();
+        info.task = self.task.trim().to_string();
+        info.device_addr = NAPSE_ADDR.read().unwrap().clone().unwrap_or_default();
+        info.mode = if self.test_mode {
+            "test"
+        } else if self.noise_mode {
+            "noise"
+        } else if self.impedance_mode {
+            "impedance"
+        } else {
+            "normal"
+        }.into();
+        for (i, label) in self.marker_labels.iter().enumerate() {
+            if !label.trim().is_empty() {
+                info.marker_labels.insert(i as u8 + 1, label.trim().to_string());
+            }
+        }
+        info.notes = self.notes.clone();
+        info
+    }
+
     fn record_button(&mut self, ui: &mut egui::Ui) {
-        let text = if self.recording {
+        // the recorder stops by itself if it fails to write to disk
+        // or when a timed recording is complete
+        if self.recorder.as_ref().is_some_and(|r| !r.is_running()) {
+            match self.recorder.take().map(|r| r.stop()) {
+                Some(Ok(path)) => println!("Recording saved to {}", path.display()),
+                Some(Err(e)) => log_err(format!("Recording failed: {}", e)),
+                None => (),
+            }
+        }
+
+        let recording = self.recorder.is_some();
+        let text = if recording {
             "Stop recording"
         } else {
             "Start recording"
         };
 
         let mut button = egui::Button::new(text);
-        if self.recording {
+        if recording {
             button = button.fill(egui::Color32::DARK_RED);
         }
 
         let is_connected = NAPSE_ADDR.read().unwrap().is_some();
 
         if ui.add(button).clicked() {
-            if !self.recording && is_connected {
-                self.recording = true;
-            } else if self.recording {
-                self.recording = false;
+            if let Some(recorder) = self.recorder.take() {
+                match recorder.stop() {
+                    Ok(path) => println!("Recording saved to {}", path.display()),
+                    Err(e) => log_err(format!("Failed to save the recording: {}", e)),
+                }
+            } else if is_connected {
+                // the info only names the file, the start is set once the
+                // dialog is closed
+                let mut info = self.recording_info();
+                let file = match &self.output_dir {
+                    Some(dir) => Some(naming::next_path(dir, &self.name_template, &info, self.output_ext)),
+                    None => {
+                        let stem = naming::file_stem(&self.name_template, &info, "");
+                        let mut dialog = FileDialog::new().set_file_name(format!("{}.{}", stem, self.output_ext));
+                        for (name, ext) in naming::FORMATS {
+                            dialog = dialog.add_filter(name, &[ext]);
+                        }
+                        dialog.save_file()
+                    }
+                };
+
+                if let Some(path) = file {
+                    let duration = self.record_for.map(|v| Duration::from_secs_f64(v * self.record_unit));
+                    info.start = Local::now();
+                    match Recorder::start(path, info, self.csv_options.clone(), duration, self.split) {
+                        Ok(recorder) => self.recorder = Some(recorder),
+                        Err(e) => log_err(format!("Failed to start recording: {}", e)),
+                    }
+                }
             }
+        }
 
-            {
-                let mut flag = crate::wave::RECORDING_FLAG.write().unwrap();
-                *flag = self.recording;
+        if let Some(recorder) = self.recorder.as_mut() {
+            let text = if recorder.is_paused() { "Resume" } else { "Pause" };
+            if ui.button(text).clicked() {
+                if recorder.is_paused() {
+                    recorder.resume();
+                } else {
+                    recorder.pause();
+                }
             }
+        }
+    }
+
+    /// Offers to recover the recording picked in the settings, that was
+    /// ongoing when NiGUI was closed.
+    fn recovery_window(&mut self, ctx: &Context) {
+        let Some(unfinished) = &self.unfinished else { return };
+        let path = unfinished.path.clone();
 
-            if self.recording {
-                let mut buffs = crate::wave::RECORDING_BUFFS.write().unwrap();
-                buffs.clear();
-                // push a vec for each column in the CSV
-                for _ in 0..(WAVE_BUFFS_NUM*2 + 1) {
-                    buffs.push(vec![]);
+        let mut recover = false;
+        let mut close = false;
+        egui::Window::new("Unfinished recording").show(ctx, |ui| {
+            ui.label(format!("The recording to {} was not stopped.", path.display()));
+            ui.horizontal(|ui| {
+                if ui.button("Recover").clicked() {
+                    recover = true;
+                }
+                if ui.button("Discard").clicked() {
+                    if let Err(e) = record::stream::discard(&path) {
+                        log_err(format!("Failed to discard the recording: {}", e));
+                    }
+                    close = true;
                 }
-            } else {
-                let buffs = crate::wave::RECORDING_BUFFS.read().unwrap();
-                let default = format!("recording-{}.csv", Utc::now().to_rfc3339());
-                let file = FileDialog::new()
-                    .set_file_name(&default)
-                    .save_file();
+            });
+        });
 
-                if let Some(path) = file {
-                    write_data_to_file(
-                        &path.to_string_lossy().to_string(),
-                        buffs.to_vec(),
-                    );
+        if recover {
+            if let Some(unfinished) = self.unfinished.take() {
+                match record::stream::recover(unfinished) {
+                    Ok(_) => println!("Recording recovered to {}", path.display()),
+                    Err(e) => log_err(format!("Failed to recover the recording: {}", e)),
                 }
             }
         }
+        if close {
+            self.unfinished = None;
+        }
     }
 }
 
@@ -230,7 +555,7 @@ impl eframe::App for MyApp {
             for (i, k) in keys.iter().enumerate() {
                 if ctx.input(|i| i.key_pressed(*k)) && connected {
                     println!("Sending mark...🦝 value={}", i + 1);
-                    send_tcp_command(0x33, &[(i + 1) as u8]).unwrap();
+                    send_tcp_command(CMD_MARK, &[(i + 1) as u8]).unwrap();
                 }
             }
 
@@ -296,7 +621,7 @@ impl eframe::App for MyApp {
                 if ui.add(egui::Button::new("Send mark")).clicked() && connected {
                     println!("Sending mark...🦝 value={}", self.mark_str);
                     let m: u8 = self.mark_str.parse().unwrap(); // TODO: Handle the error better
-                    send_tcp_command(0x33, &[m]).unwrap();
+                    send_tcp_command(CMD_MARK, &[m]).unwrap();
                 }
 
                 ui.label(" (use QWERTY to send marks 1-6)");
@@ -310,9 +635,9 @@ impl eframe::App for MyApp {
                 if ui.add(test_button).clicked() && !self.impedance_mode && !self.noise_mode && connected {
                     self.test_mode = !self.test_mode;
                     if self.test_mode {
-                        send_tcp_command(0x77, &[1]).unwrap(); // test ON
+                        send_tcp_command(CMD_TEST_ON, &[1]).unwrap(); // test ON
                     } else {
-                        send_tcp_command(0xaa, &[1]).unwrap(); // test OFF
+                        send_tcp_command(CMD_MODE_OFF, &[1]).unwrap(); // test OFF
                     }
                 }
 
@@ -325,14 +650,41 @@ impl eframe::App for MyApp {
                 if ui.add(noise_button).clicked() && !self.test_mode && !self.impedance_mode && connected {
                     self.noise_mode = !self.noise_mode;
                     if self.noise_mode {
-                        send_tcp_command(0x66, &[1]).unwrap();
+                        send_tcp_command(CMD_NOISE_ON, &[1]).unwrap();
                     } else {
-                        send_tcp_command(0xaa, &[1]).unwrap();
+                        send_tcp_command(CMD_MODE_OFF, &[1]).unwrap();
                     }
                 }
 
                 ui.separator();
 
+                let session_button = egui::Button::new("Session").selected(self.session_open);
+                if ui.add(session_button).clicked() {
+                    self.session_open = !self.session_open;
+                }
+                if self.session_open {
+                    self.session_menu(ctx);
+                }
+
+                let settings_button = egui::Button::new("Settings").selected(self.settings_open);
+                if ui.add(settings_button).clicked() {
+                    self.settings_open = !self.settings_open;
+                    if !self.settings_open {
+                        wave::bands::save_edits();
+                    }
+                }
+                if self.settings_open {
+                    self.settings_menu(ctx);
+                }
+
+                let viewer_button = egui::Button::new("Viewer").selected(self.viewer_open);
+                if ui.add(viewer_button).clicked() {
+                    self.viewer_open = !self.viewer_open;
+                }
+                if self.viewer_open {
+                    self.viewer.show(ctx, &mut self.viewer_open);
+                }
+
                 let mut plugins_button = egui::Button::new("Plugins");
                 if self.plugins_cfg.is_some() {
                     plugins_button = plugins_button.fill(egui::Color32::DARK_GREEN);
@@ -354,12 +706,15 @@ impl eframe::App for MyApp {
                     }
                 }
                 if let Some(cfg) = &self.plugins_cfg.clone() {
-                    self.plugins_menu(&ctx, cfg);
+                    self.plugins_menu(ctx, cfg);
                 }
+                self.recovery_window(ctx);
             });
             ui.separator();
 
-            wave::plot_waves(ui);
+            self.live_view.mark_labels.clone_from(&self.marker_labels);
+            wave::live_controls(ui, &mut self.live_view);
+            wave::plot_waves(ui, &mut self.live_view);
 
             // Draw error messages in the bottom of the UI
             ui.separator();
@@ -388,32 +743,8 @@ impl eframe::App for MyApp {
 
     }
 
-}
-
-fn write_data_to_file(fname: &str, bufs: Vec<Vec<f32>>) {
-    use std::fs::File;
-    use std::io::Write;
-
-    let mut out = File::create(fname).unwrap();
-    let num_bufs = bufs.len();
-    for i in 0..WAVE_BUFFS_NUM {
-        write!(out, "channel-{},", i).unwrap();
-    }
-
-    for i in 0..WAVE_BUFFS_NUM {
-        write!(out, "status ch-{},", i).unwrap();
+    fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+        wave::bands::save_edits();
     }
 
-    write!(out, "mark").unwrap();
-
-    write!(out, "\n").unwrap();
-    for j in 0..bufs[0].len() {
-        // for each data point
-        for i in 0..num_bufs - 1 {
-            // for each channel
-            write!(out, "{},", bufs[i][j]).unwrap();
-        }
-        write!(out, "{}", bufs[num_bufs - 1][j]).unwrap();
-        write!(out, "\n").unwrap();
-    }
 }
@@ -0,0 +1,310 @@
+//! Headless recorder: acquires from a NAPSE and records to a file without the
+//! GUI, e.g. on a lab PC without display or from scripts.
+//!
+//! ```text
+//! nigui-rec 172.16.30.150 -o rest.edf --duration 5m --subject 01 --task rest
+//! nigui-rec --recover rest.edf
+//! ```
+
+use std::path::PathBuf;
+use std::process::exit;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::Local;
+
+use nigui::record::csv::CsvOptions;
+use nigui::record::stream::{self, Recorder, Split};
+use nigui::record::{naming, RecordingInfo};
+use nigui::wave::{RECORDING_BUFFS, WAVE_BUFFS_NUM};
+use nigui::wifi::{self, CH_STATUS, ERRORS, MARKER_ADDR, NAPSE_ADDR, PACKAGE_RATE};
+
+const USAGE: &str = "\
+Usage: nigui-rec <NAPSE address> [options]
+       nigui-rec --recover <file>
+
+Records from a NAPSE until the duration is reached or Ctrl+C is pressed.
+
+Output (one of):
+  -o, --output <file>      file to record to, the format is given by the
+                           extension: csv, edf, bdf, vhdr, npz or ngr
+  --dir <dir>              directory to record to, the file is named after
+                           --template with the --format extension
+  --template <template>    file name template (default: {subject}_{session}_{task}_{date}_{counter})
+  --format <ext>           format of the files in --dir (default: csv)
+
+Options:
+  -d, --duration <time>    record for this long: 90, 90s, 5m or 1h
+  --split-every <time>     split the recording into parts of this length
+  --split-size <MB>        split the recording into parts of this size
+  --test                   enable the test signal
+  --noise                  enable the noise measurement mode
+  --impedance              enable the impedance measurement mode
+  --marker-addr <addr>     listen for marks on this UDP address (e.g. 127.0.0.1:20001)
+  --subject <id>, --session <id>, --task <name>, --notes <text>
+                           recording information for the sidecar and file names
+  --recover <file>         write <file> from the data of a recording to it
+                           that was not stopped, and exit
+  -h, --help               show this help";
+
+/// Time to wait for the first packages of the NAPSE.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Args {
+    addr: String,
+    output: Option<PathBuf>,
+    dir: Option<PathBuf>,
+    template: String,
+    format: String,
+    duration: Option<Duration>,
+    split: Split,
+    mode: &'static str,
+    marker_addr: Option<String>,
+    subject: String,
+    session: String,
+    task: String,
+    notes: String,
+    /// Output of an unfinished recording to recover instead of recording
+    recover: Option<PathBuf>,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args {
+        addr: String::new(),
+        output: None,
+        dir: None,
+        template: naming::DEFAULT_TEMPLATE.into(),
+        format: "csv".into(),
+        duration: None,
+        split: Split::default(),
+        mode: "normal",
+        marker_addr: None,
+        subject: String::new(),
+        session: String::new(),
+        task: String::new(),
+        notes: String::new(),
+        recover: None,
+    };
+
+    let mut argv = std::env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        let mut value = || argv.next().ok_or(format!("Missing value of {}", arg));
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                exit(0);
+            }
+            "-o" | "--output" => args.output = Some(value()?.into()),
+            "--dir" => args.dir = Some(value()?.into()),
+            "--template" => args.template = value()?,
+            "--format" => args.format = value()?.trim_start_matches('.').to_string(),
+            "-d" | "--duration" => args.duration = Some(parse_duration(&value()?)?),
+            "--split-every" => args.split.minutes = Some(parse_duration(&value()?)?.as_secs_f64() / 60.0),
+            "--split-size" => {
+                let v = value()?;
+                let mb = v.parse().ok().filter(|mb: &f64| *mb > 0.0);
+                args.split.megabytes = Some(mb.ok_or(format!("Invalid size {}", v))?);
+            }
+            "--test" => args.mode = "test",
+            "--noise" => args.mode = "noise",
+            "--impedance" => args.mode = "impedance",
+            "--marker-addr" => args.marker_addr = Some(value()?),
+            "--subject" => args.subject = value()?,
+            "--session" => args.session = value()?,
+            "--task" => args.task = value()?,
+            "--notes" => args.notes = value()?,
+            "--recover" => args.recover = Some(value()?.into()),
+            _ if arg.starts_with('-') => return Err(format!("Unknown option {}", arg)),
+            _ if args.addr.is_empty() => args.addr = arg,
+            _ => return Err(format!("Unexpected argument {}", arg)),
+        }
+    }
+
+    if args.recover.is_some() {
+        return Ok(args);
+    }
+    if args.addr.is_empty() {
+        return Err("Missing the NAPSE address".into());
+    }
+    if args.output.is_some() == args.dir.is_some() {
+        return Err("Give either --output or --dir".into());
+    }
+    if let Some(output) = &args.output {
+        args.format = output.extension().map_or(String::new(), |e| e.to_string_lossy().into());
+    }
+    if !naming::FORMATS.iter().any(|(_, ext)| *ext == args.format) {
+        return Err(format!("Unsupported format {}", args.format));
+    }
+    Ok(args)
+}
+
+/// Parses `90`, `90s`, `5m` or `1h`.
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (value, unit) = match s.trim().char_indices().find(|(_, c)| c.is_alphabetic()) {
+        Some((i, _)) => s.trim().split_at(i),
+        None => (s.trim(), "s"),
+    };
+    let seconds = match unit {
+        "s" => 1.0,
+        "m" | "min" => 60.0,
+        "h" => 3600.0,
+        _ => return Err(format!("Invalid duration {}", s)),
+    };
+    match value.parse::<f64>() {
+        Ok(v) if v > 0.0 => Ok(Duration::from_secs_f64(v * seconds)),
+        _ => Err(format!("Invalid duration {}", s)),
+    }
+}
+
+fn main() {
+    let args = parse_args().unwrap_or_else(|e| {
+        eprintln!("{}\n\n{}", e, USAGE);
+        exit(2);
+    });
+
+    if let Some(path) = &args.recover {
+        let Some(unfinished) = stream::unfinished(path) else {
+            eprintln!("{} has no unfinished recording", path.display());
+            exit(1);
+        };
+        match stream::recover(unfinished) {
+            Ok(_) => println!("Recovered the unfinished recording to {}", path.display()),
+            Err(e) => {
+                eprintln!("Failed to recover the unfinished recording {}: {}", path.display(), e);
+                exit(1);
+            }
+        }
+        exit(0);
+    }
+
+    let failed = Arc::new(AtomicBool::new(false));
+    let stop = Arc::new(AtomicBool::new(false));
+    {
+        let stop = stop.clone();
+        if let Err(e) = ctrlc::set_handler(move || stop.store(true, Ordering::SeqCst)) {
+            eprintln!("Failed to set the Ctrl+C handler: {}", e);
+            exit(1);
+        }
+    }
+
+    // same acquisition threads as the GUI
+    *NAPSE_ADDR.write().unwrap() = Some(args.addr.clone());
+    {
+        let failed = failed.clone();
+        let impedance = args.mode == "impedance";
+        std::thread::spawn(move || {
+            if let Err(e) = wifi::read_napse(impedance) {
+                eprintln!("Acquisition failed: {}", e);
+                failed.store(true, Ordering::SeqCst);
+            }
+        });
+    }
+    if let Some(addr) = &args.marker_addr {
+        *MARKER_ADDR.write().unwrap() = Some(addr.clone());
+        std::thread::spawn(wifi::marker_server);
+    }
+
+    println!("Connecting to {}...", args.addr);
+    let connecting = Instant::now();
+    while *PACKAGE_RATE.read().unwrap() == 0 {
+        if failed.load(Ordering::SeqCst) || stop.load(Ordering::SeqCst) {
+            exit(1);
+        }
+        if connecting.elapsed() > CONNECT_TIMEOUT {
+            eprintln!("No data received from {}", args.addr);
+            exit(1);
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+
+    // same commands as the buttons of the GUI, the impedance mode is
+    // enabled when the acquisition starts
+    let command = match args.mode {
+        "test" => Some((wifi::CMD_TEST_ON, &[1][..])),
+        "noise" => Some((wifi::CMD_NOISE_ON, &[1][..])),
+        _ => None,
+    };
+    if let Some((command, payload)) = command {
+        if let Err(e) = wifi::send_tcp_command(command, payload) {
+            eprintln!("Failed to enable the {} mode: {}", args.mode, e);
+            exit(1);
+        }
+    }
+
+    let mut info = RecordingInfo::new(Local::now());
+    info.device_addr = args.addr.clone();
+    info.mode = args.mode.into();
+    info.subject = args.subject.clone();
+    info.session = args.session.clone();
+    info.task = args.task.clone();
+    info.notes = args.notes.clone();
+
+    let path = match (&args.output, &args.dir) {
+        (Some(path), _) => path.clone(),
+        (None, Some(dir)) => naming::next_path(dir, &args.template, &info, &args.format),
+        (None, None) => unreachable!(),
+    };
+    let recorder = match Recorder::start(path.clone(), info.clone(), CsvOptions::default(), args.duration, args.split) {
+        Ok(recorder) => recorder,
+        Err(e) => {
+            eprintln!("Failed to start recording to {}: {}", path.display(), e);
+            exit(1);
+        }
+    };
+    match args.duration {
+        Some(d) => println!("Recording to {} for {:.0} s, press Ctrl+C to stop", path.display(), d.as_secs_f64()),
+        None => println!("Recording to {}, press Ctrl+C to stop", path.display()),
+    }
+
+    let started = Instant::now();
+    let mut num_errors = ERRORS.read().unwrap().len();
+    while recorder.is_running() && !stop.load(Ordering::SeqCst) && !failed.load(Ordering::SeqCst) {
+        std::thread::sleep(Duration::from_secs(1));
+        print_health(&recorder, &info, started.elapsed());
+        let errors = ERRORS.read().unwrap();
+        for e in errors.iter().skip(num_errors) {
+            eprintln!("  error: {}", e);
+        }
+        num_errors = errors.len();
+    }
+
+    if matches!(args.mode, "test" | "noise") {
+        let _ = wifi::send_tcp_command(wifi::CMD_MODE_OFF, &[1]);
+    }
+    match recorder.stop() {
+        Ok(path) => println!("Recording saved to {}", path.display()),
+        Err(e) => {
+            eprintln!("Failed to save the recording: {}", e);
+            exit(1);
+        }
+    }
+    if failed.load(Ordering::SeqCst) {
+        exit(1);
+    }
+}
+
+/// Prints a line with the acquisition health: elapsed time, samples,
+/// package rate, buffered samples and disconnected electrodes.
+fn print_health(recorder: &Recorder, info: &RecordingInfo, elapsed: Duration) {
+    let secs = elapsed.as_secs();
+    let rate = *PACKAGE_RATE.read().unwrap();
+    let buffered = RECORDING_BUFFS.read().unwrap().first().map_or(0, |b| b.len());
+    let status = *CH_STATUS.read().unwrap();
+    let lead_off: Vec<&str> = (0..WAVE_BUFFS_NUM)
+        .filter(|ch| !status[*ch])
+        .map(|ch| info.labels[ch].as_str())
+        .collect();
+
+    println!(
+        "[{:02}:{:02}:{:02}] {} samples | {} pkg/s (expected {}) | buffered {} | lead-off: {}",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        recorder.num_samples(),
+        rate,
+        info.sampling_rate,
+        buffered,
+        if lead_off.is_empty() { "none".into() } else { lead_off.join(", ") },
+    );
+}
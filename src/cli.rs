@@ -0,0 +1,277 @@
+//! Subcommands of `nigui` to work with recordings from the command line:
+//!
+//! ```text
+//! nigui convert rest.csv rest.edf --channels 1,2 --highpass 0.5 --notch 50 --rate 125
+//! nigui info rest.edf
+//! ```
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use nigui::record::convert::{self, ConvertOptions};
+use nigui::record::{self, integrity, meta, naming, Gap, Recording, BOUNDARY};
+
+pub const USAGE: &str = "\
+Usage: nigui [command]
+
+Without a command, starts the GUI.
+
+Commands:
+  convert <input> <output> [options]   convert a recording to another format
+  info <file>                          print a summary of a recording
+
+Run `nigui <command> --help` for the options of each command.";
+
+const CONVERT_USAGE: &str = "\
+Usage: nigui convert <input> <output> [options]
+
+Converts a recording (csv, edf, bdf, vhdr, npz or ngr) to the format given
+by the extension of the output: csv, edf, bdf, vhdr, npz or ngr. The sidecar
+with the recording information is written next to the output.
+
+Options:
+  --channels <list>     channels to keep, in order, by number (from 1) or
+                        label, separated by commas: 1,2,5 or CH-1,CH-2
+  --highpass <Hz>       Butterworth high-pass filter
+  --lowpass <Hz>        Butterworth low-pass filter
+  --notch <Hz>          notch filter for the power line noise (50 or 60)
+  --rate <Hz>           resample to this sampling rate
+  -h, --help            show this help";
+
+const INFO_USAGE: &str = "\
+Usage: nigui info <file>
+
+Prints the duration, number of samples, marks per code, lead-off percentage
+per channel and gaps of a recording. Fails if the recording doesn't match the
+checksum in its sidecar.";
+
+/// Runs the `convert` subcommand. Returns the exit code.
+pub fn convert(args: &[String]) -> i32 {
+    let (input, output, opts) = match parse_convert(args) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            println!("{}", CONVERT_USAGE);
+            return 0;
+        }
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, CONVERT_USAGE);
+            return 2;
+        }
+    };
+
+    let rec = match record::load(&input) {
+        Ok(rec) => rec,
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", input.display(), e);
+            return 1;
+        }
+    };
+    let channels = match &opts.channels {
+        Some(list) => match channel_indexes(list, &rec) {
+            Ok(channels) => Some(channels),
+            Err(e) => {
+                eprintln!("{}", e);
+                return 2;
+            }
+        },
+        None => None,
+    };
+    let opts = ConvertOptions { channels, ..opts.convert };
+    let rec = match convert::convert(rec, &opts) {
+        Ok(rec) => rec,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 2;
+        }
+    };
+
+    let res = record::export(&output, &rec)
+        .and_then(|_| meta::write_sidecar(&output, &rec.info, &rec.annotations))
+        .and_then(|_| integrity::save_summary(&output, &rec));
+    match res {
+        Ok(_) => {
+            println!(
+                "Wrote {} ({} channels, {} samples at {} Hz)",
+                output.display(),
+                rec.channels.len(),
+                rec.len(),
+                rec.info.sampling_rate
+            );
+            0
+        }
+        Err(e) => {
+            eprintln!("Failed to write {}: {}", output.display(), e);
+            1
+        }
+    }
+}
+
+struct ConvertArgs {
+    /// Channels as given in the command line, resolved once the input is read.
+    channels: Option<String>,
+    convert: ConvertOptions,
+}
+
+/// Parses the arguments of `convert`. `None` if the help was asked for.
+fn parse_convert(args: &[String]) -> Result<Option<(PathBuf, PathBuf, ConvertArgs)>, String> {
+    let mut paths = vec![];
+    let mut opts = ConvertArgs { channels: None, convert: ConvertOptions::default() };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("Missing value of {}", arg));
+        let mut number = || -> Result<f32, String> {
+            let v = value()?;
+            v.parse().ok().filter(|f: &f32| *f > 0.0).ok_or(format!("Invalid value of {}: {}", arg, v))
+        };
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "--channels" => opts.channels = Some(value()?.clone()),
+            "--highpass" => opts.convert.highpass = Some(number()?),
+            "--lowpass" => opts.convert.lowpass = Some(number()?),
+            "--notch" => opts.convert.notch = Some(number()?),
+            "--rate" => opts.convert.sampling_rate = Some(number()?.round() as u32),
+            _ if arg.starts_with('-') => return Err(format!("Unknown option {}", arg)),
+            _ => paths.push(PathBuf::from(arg)),
+        }
+    }
+
+    match <[PathBuf; 2]>::try_from(paths) {
+        Ok([input, output]) => Ok(Some((input, output, opts))),
+        Err(_) => Err("Give an input and an output file".into()),
+    }
+}
+
+/// Resolves a comma separated list of channel numbers (from 1) or labels.
+fn channel_indexes(list: &str, rec: &Recording) -> Result<Vec<usize>, String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|c| !c.is_empty())
+        .map(|c| {
+            let by_label = rec.info.labels.iter().position(|l| l.eq_ignore_ascii_case(c));
+            let by_number = c.parse::<usize>().ok().filter(|n| (1..=rec.channels.len()).contains(n));
+            by_label
+                .or(by_number.map(|n| n - 1))
+                .ok_or(format!("There is no channel {}", c))
+        })
+        .collect()
+}
+
+/// Runs the `info` subcommand. Returns the exit code.
+pub fn info(args: &[String]) -> i32 {
+    let path = match args {
+        [arg] if arg == "-h" || arg == "--help" => {
+            println!("{}", INFO_USAGE);
+            return 0;
+        }
+        [path] => Path::new(path),
+        _ => {
+            eprintln!("Give one file\n\n{}", INFO_USAGE);
+            return 2;
+        }
+    };
+    match record::load(path) {
+        Ok(rec) => {
+            print_info(path, &rec);
+            0
+        }
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", path.display(), e);
+            1
+        }
+    }
+}
+
+fn print_info(path: &Path, rec: &Recording) {
+    let ext = path.extension().unwrap_or_default().to_string_lossy();
+    let format = naming::FORMATS.iter().find(|(_, e)| *e == ext).map_or("?", |(name, _)| name);
+    let info = &rec.info;
+
+    println!("File:          {}", path.display());
+    println!("Format:        {}", format);
+    println!("Start:         {}", info.start.format("%Y-%m-%d %H:%M:%S%.3f"));
+    println!("Duration:      {} ({:.3} s)", hms(rec.duration()), rec.duration());
+    println!("Samples:       {} per channel at {} Hz", rec.len(), info.sampling_rate);
+    if let Some(part) = info.part {
+        println!("Part:          {} (from sample {} of the recording)", part, info.first_sample);
+    }
+    println!("Channels:      {}", info.labels.join(", "));
+    if !info.prefilter.is_empty() {
+        println!("Filters:       {}", info.prefilter);
+    }
+    for (name, value) in [("Subject", &info.subject), ("Session", &info.session), ("Task", &info.task)] {
+        if !value.is_empty() {
+            println!("{:<15}{}", format!("{}:", name), value);
+        }
+    }
+
+    let mut marks = BTreeMap::new();
+    for (_, code) in rec.events() {
+        *marks.entry(code).or_insert(0) += 1;
+    }
+    println!("\nMarks:{}", if marks.is_empty() { "         none" } else { "" });
+    for (code, count) in marks {
+        println!("  {:>3} {:<20} {}", code, info.mark_label(code), count);
+    }
+
+    println!("\nLead-off:");
+    for (ch, label) in info.labels.iter().enumerate().take(rec.status.len()) {
+        println!("  {:<10} {:>6.2} %", label, rec.lead_off_ratio(ch) * 100.0);
+    }
+
+    let pauses: Vec<_> = rec.annotations.iter().filter(|a| a.label == BOUNDARY).collect();
+    if !pauses.is_empty() {
+        println!("\nPauses:");
+        for a in pauses {
+            println!("  at {} ({})", hms(a.onset), a.description);
+        }
+    }
+
+    // formats without sample times have the gaps found on save in the sidecar
+    let name = path.file_name().map(|n| n.to_string_lossy());
+    let integrity = meta::read_integrity(path).filter(|i| i["file"].as_str() == name.as_deref());
+    let gaps = match &integrity {
+        Some(integrity) if rec.times.is_empty() => integrity["gaps"]
+            .members()
+            .filter_map(|g| {
+                Some(Gap {
+                    sample: g["sample"].as_usize()?,
+                    onset: g["onset"].as_f64()?,
+                    duration: g["duration"].as_f64()?,
+                })
+            })
+            .collect(),
+        _ => rec.gaps(),
+    };
+    if rec.times.is_empty() && integrity.is_none() {
+        println!("\nGaps:          unknown, the file has no sample times");
+    } else if gaps.is_empty() {
+        println!("\nGaps:          none");
+    } else {
+        let total: f64 = gaps.iter().map(|g| g.duration).sum();
+        println!("\nGaps:          {} ({:.3} s in total)", gaps.len(), total);
+        for gap in gaps {
+            println!("  at {} (sample {}): {:.3} s", hms(gap.onset), gap.sample, gap.duration);
+        }
+    }
+
+    if let Some(integrity) = integrity {
+        // load fails on a wrong checksum, so here it is always right
+        println!("\nChecksum:      verified (SHA-256 {})", integrity["sha256"]);
+        if let Some(expected) = integrity["expected_samples"].as_usize() {
+            println!("Expected:      {} samples from the elapsed time", expected);
+        }
+    }
+
+    if !rec.annotations.is_empty() {
+        let bad: Vec<_> = rec.annotations.iter().filter(|a| a.is_bad()).collect();
+        let bad_time: f64 = bad.iter().map(|a| a.duration).sum();
+        println!("\nAnnotations:   {} ({} bad segments, {:.3} s)", rec.annotations.len(), bad.len(), bad_time);
+    }
+}
+
+/// Formats seconds as `hh:mm:ss.mmm`.
+fn hms(seconds: f64) -> String {
+    let ms = (seconds * 1000.0).round() as u64;
+    format!("{:02}:{:02}:{:02}.{:03}", ms / 3_600_000, ms / 60_000 % 60, ms / 1000 % 60, ms % 1000)
+}
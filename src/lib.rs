@@ -5,13 +5,28 @@ extern crate lazy_static;
 
 mod app;
 mod plugins;
+pub mod record;
+mod viewer;
 pub mod wave;
 pub mod wifi;
 pub use app::MyApp;
 
+use std::env;
+use std::path::PathBuf;
+
 use wifi::{ERRORS, NOTIFICATIONS};
 
 pub fn log_err(msg: String) {
     ERRORS.write().unwrap().push(msg.clone());
     NOTIFICATIONS.write().unwrap().push(msg);
 }
+
+/// Directory of the settings saved by the app: `nigui` in the user
+/// configuration directory, or the current directory if there is none.
+pub fn config_dir() -> PathBuf {
+    let base = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("APPDATA").map(PathBuf::from))
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+    base.map_or_else(PathBuf::new, |dir| dir.join("nigui"))
+}
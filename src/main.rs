@@ -2,17 +2,30 @@ use nigui::{wave, wifi::{self, NAPSE_ADDR}, MyApp};
 use std::time::Duration;
 // use tokio::runtime::Runtime;
 
+mod cli;
+
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("convert") => std::process::exit(cli::convert(&args[1..])),
+        Some("info") => std::process::exit(cli::info(&args[1..])),
+        Some("-h") | Some("--help") => {
+            println!("{}", cli::USAGE);
+            return;
+        }
+        Some(cmd) => {
+            eprintln!("Unknown command {}\n\n{}", cmd, cli::USAGE);
+            std::process::exit(2);
+        }
+        None => {}
+    }
+
     println!("Starting UI... 🧠🦝🐙🐰");
 
     std::thread::spawn(|| {
-        loop {
-            if let Err(e) =  wifi::read_napse() { // read data in a loop
-                nigui::log_err(e.to_string());
-                *NAPSE_ADDR.write().unwrap() = None;
-            } else {
-                break
-            }
+        while let Err(e) = wifi::read_napse(false) { // read data in a loop
+            nigui::log_err(e.to_string());
+            *NAPSE_ADDR.write().unwrap() = None;
         }
     });
 
@@ -0,0 +1,193 @@
+//! Export of recordings to a BIDS-EEG dataset. See
+//! <https://bids-specification.readthedocs.io/en/stable/modality-specific-files/electroencephalography.html>.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use json::{object, JsonValue};
+
+use super::{edf, meta, Recording};
+
+const BIDS_VERSION: &str = "1.8.0";
+
+/// Channels with the electrode disconnected for a larger fraction of the
+/// recording than this are marked as bad in `channels.tsv`.
+const BAD_CHANNEL_LEAD_OFF: f64 = 0.1;
+
+/// Participant information for `participants.tsv`.
+#[derive(Default, Clone)]
+pub struct Participant {
+    pub age: String,
+    pub sex: String,
+}
+
+/// Writes the recording into the BIDS dataset in `root`, creating the
+/// dataset if needed. The subject, session and task labels are taken from
+/// the recording info, subject and task are required.
+/// Returns the path of the written EDF file.
+pub fn export(root: &Path, rec: &Recording, participant: &Participant) -> io::Result<PathBuf> {
+    let subject = label(&rec.info.subject);
+    let session = label(&rec.info.session);
+    let task = label(&rec.info.task);
+    if subject.is_empty() || task.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "BIDS export needs a subject ID and a task",
+        ));
+    }
+
+    let mut dir = root.join(format!("sub-{}", subject));
+    let mut prefix = format!("sub-{}", subject);
+    if !session.is_empty() {
+        dir.push(format!("ses-{}", session));
+        prefix.push_str(&format!("_ses-{}", session));
+    }
+    dir.push("eeg");
+    prefix.push_str(&format!("_task-{}", task));
+    fs::create_dir_all(&dir)?;
+
+    write_dataset_description(root)?;
+    write_participant(root, &subject, participant)?;
+
+    let edf_path = dir.join(format!("{}_eeg.edf", prefix));
+    edf::write_edf(&edf_path, rec)?;
+
+    let sidecar = object! {
+        TaskName: rec.info.task.as_str(),
+        SamplingFrequency: rec.info.sampling_rate,
+        EEGChannelCount: rec.channels.len(),
+        EOGChannelCount: 0,
+        ECGChannelCount: 0,
+        EMGChannelCount: 0,
+        MiscChannelCount: 0,
+        TriggerChannelCount: 0,
+        PowerLineFrequency: "n/a",
+        EEGReference: "n/a",
+        SoftwareFilters: software_filters(&rec.info.prefilter),
+        RecordingDuration: rec.duration(),
+        RecordingType: "continuous",
+        Manufacturer: "NIT",
+        ManufacturersModelName: "NAPSE",
+        SoftwareVersions: env!("CARGO_PKG_VERSION"),
+    };
+    fs::write(dir.join(format!("{}_eeg.json", prefix)), sidecar.pretty(4))?;
+
+    let mut channels = fs::File::create(dir.join(format!("{}_channels.tsv", prefix)))?;
+    writeln!(channels, "name\ttype\tunits\tlow_cutoff\thigh_cutoff\tstatus\tstatus_description")?;
+    for (ch, name) in rec.info.labels.iter().take(rec.channels.len()).enumerate() {
+        let lead_off = rec.lead_off_ratio(ch);
+        let status = if lead_off > BAD_CHANNEL_LEAD_OFF { "bad" } else { "good" };
+        writeln!(
+            channels,
+            "{}\tEEG\tuV\t{}\t{}\t{}\tlead-off during {:.1}% of the recording",
+            name,
+            cutoff(&rec.info.prefilter, "HP"),
+            cutoff(&rec.info.prefilter, "LP"),
+            status,
+            100.0 * lead_off,
+        )?;
+    }
+
+    meta::write_events(&dir.join(format!("{}_events.tsv", prefix)), rec)?;
+
+    Ok(edf_path)
+}
+
+/// Whether `path` is a recording of a BIDS dataset (`sub-01_task-rest_eeg.edf`).
+pub fn is_bids_recording(path: &Path) -> bool {
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or_default();
+    stem.starts_with("sub-") && stem.ends_with("_eeg")
+}
+
+/// Path of the events file of the BIDS recording in `path`
+/// (`sub-01_task-rest_eeg.edf` -> `sub-01_task-rest_events.tsv`).
+pub fn events_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let prefix = stem.strip_suffix("_eeg").unwrap_or(&stem);
+    path.with_file_name(format!("{}_events.tsv", prefix))
+}
+
+/// Saves the annotations of the BIDS recording in `path` to its NiGUI
+/// sidecar and to its BIDS `events.tsv`.
+pub fn write_annotations(path: &Path, rec: &Recording) -> io::Result<()> {
+    meta::write_sidecar(path, &rec.info, &rec.annotations)?;
+    meta::write_events(&events_path(path), rec)
+}
+
+/// `SoftwareFilters` of the sidecar: the filters in the prefilter
+/// description (`HP:0.5Hz LP:40Hz N:50Hz`), "n/a" if the samples were
+/// recorded unfiltered.
+fn software_filters(prefilter: &str) -> JsonValue {
+    if prefilter.trim().is_empty() {
+        return "n/a".into();
+    }
+    let mut filters = JsonValue::new_object();
+    for (kind, name) in [("HP", "Butterworth high-pass"), ("LP", "Butterworth low-pass"), ("N", "Notch")] {
+        if let Some(f) = filter_frequency(prefilter, kind) {
+            filters[name] = object! { cutoff_hz: f };
+        }
+    }
+    if filters.is_empty() {
+        filters["description"] = prefilter.into();
+    }
+    filters
+}
+
+/// Cutoff of the `HP` or `LP` filter in the prefilter description, "n/a" if
+/// there is none.
+fn cutoff(prefilter: &str, kind: &str) -> String {
+    filter_frequency(prefilter, kind).map_or("n/a".into(), |f| f.to_string())
+}
+
+fn filter_frequency(prefilter: &str, kind: &str) -> Option<f64> {
+    prefilter
+        .split_whitespace()
+        .rev()
+        .find_map(|f| f.strip_prefix(kind)?.strip_prefix(':')?.strip_suffix("Hz")?.parse().ok())
+}
+
+/// Writes `dataset_description.json` if the dataset doesn't have one yet.
+fn write_dataset_description(root: &Path) -> io::Result<()> {
+    let path = root.join("dataset_description.json");
+    if path.exists() {
+        return Ok(());
+    }
+    let description = object! {
+        Name: root.file_name().map_or("NiGUI".into(), |n| n.to_string_lossy().to_string()),
+        BIDSVersion: BIDS_VERSION,
+        DatasetType: "raw",
+        GeneratedBy: [object! { Name: "NiGUI", Version: env!("CARGO_PKG_VERSION") }],
+    };
+    fs::write(path, description.pretty(4))
+}
+
+/// Adds (or replaces) the row of the subject in `participants.tsv`.
+fn write_participant(root: &Path, subject: &str, participant: &Participant) -> io::Result<()> {
+    let path = root.join("participants.tsv");
+    let id = format!("sub-{}", subject);
+    let na = |s: &str| if s.trim().is_empty() { "n/a".to_string() } else { s.trim().to_string() };
+
+    let mut rows: Vec<String> = match fs::read_to_string(&path) {
+        Ok(text) => text
+            .lines()
+            .skip(1)
+            .filter(|l| l.split('\t').next() != Some(id.as_str()))
+            .map(String::from)
+            .collect(),
+        Err(_) => vec![],
+    };
+    rows.push(format!("{}\t{}\t{}", id, na(&participant.age), na(&participant.sex)));
+
+    let mut out = fs::File::create(path)?;
+    writeln!(out, "participant_id\tage\tsex")?;
+    for row in rows {
+        writeln!(out, "{}", row)?;
+    }
+    Ok(())
+}
+
+/// BIDS labels may only contain letters and numbers.
+fn label(s: &str) -> String {
+    s.chars().filter(|c| c.is_ascii_alphanumeric()).collect()
+}
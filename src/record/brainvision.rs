@@ -0,0 +1,261 @@
+//! BrainVision Core Data Format reader and writer (`.vhdr` header, `.vmrk` markers and
+//! `.eeg` binary data). See <https://www.brainproducts.com/support-resources/brainvision-core-data-format-1-0/>.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use chrono::prelude::*;
+
+use super::{Annotation, Recording, RecordingInfo, BOUNDARY};
+use crate::wave::FULL_SCALE_UV;
+
+/// Writes the recording as a BrainVision data set. `path` is the header file
+/// (`.vhdr`), the marker (`.vmrk`) and data (`.eeg`) files are written next
+/// to it with the same name. Data is stored as multiplexed float32 in µV.
+pub fn write_brainvision(path: &Path, rec: &Recording) -> io::Result<()> {
+    let eeg_path = path.with_extension("eeg");
+    let vmrk_path = path.with_extension("vmrk");
+    let file_name = |p: &Path| p.file_name().unwrap_or_default().to_string_lossy().to_string();
+    let (eeg_name, vmrk_name) = (file_name(&eeg_path), file_name(&vmrk_path));
+
+    // header
+    let mut vhdr = BufWriter::new(File::create(path)?);
+    writeln!(vhdr, "Brain Vision Data Exchange Header File Version 1.0")?;
+    writeln!(vhdr, "; Data created by NiGUI")?;
+    writeln!(vhdr)?;
+    writeln!(vhdr, "[Common Infos]")?;
+    writeln!(vhdr, "Codepage=UTF-8")?;
+    writeln!(vhdr, "DataFile={}", eeg_name)?;
+    writeln!(vhdr, "MarkerFile={}", vmrk_name)?;
+    writeln!(vhdr, "DataFormat=BINARY")?;
+    writeln!(vhdr, "; Data orientation: MULTIPLEXED=ch1,pt1, ch2,pt1 ...")?;
+    writeln!(vhdr, "DataOrientation=MULTIPLEXED")?;
+    writeln!(vhdr, "NumberOfChannels={}", rec.channels.len())?;
+    writeln!(vhdr, "; Sampling interval in microseconds")?;
+    writeln!(vhdr, "SamplingInterval={}", 1e6 / rec.info.sampling_rate as f64)?;
+    writeln!(vhdr)?;
+    writeln!(vhdr, "[Binary Infos]")?;
+    writeln!(vhdr, "BinaryFormat=IEEE_FLOAT_32")?;
+    writeln!(vhdr)?;
+    writeln!(vhdr, "[Channel Infos]")?;
+    writeln!(vhdr, "; Each entry: Ch<Channel number>=<Name>,<Reference channel name>,")?;
+    writeln!(vhdr, "; <Resolution in \"Unit\">,<Unit>, Future extensions..")?;
+    for (i, label) in rec.info.labels.iter().take(rec.channels.len()).enumerate() {
+        writeln!(vhdr, "Ch{}={},,1,µV", i + 1, escape(label))?;
+    }
+    writeln!(vhdr)?;
+    writeln!(vhdr, "[Comment]")?;
+    writeln!(vhdr, "Prefilter: {}", rec.info.prefilter)?;
+    vhdr.flush()?;
+
+    // markers
+    let mut vmrk = BufWriter::new(File::create(&vmrk_path)?);
+    writeln!(vmrk, "Brain Vision Data Exchange Marker File, Version 1.0")?;
+    writeln!(vmrk)?;
+    writeln!(vmrk, "[Common Infos]")?;
+    writeln!(vmrk, "Codepage=UTF-8")?;
+    writeln!(vmrk, "DataFile={}", eeg_name)?;
+    writeln!(vmrk)?;
+    writeln!(vmrk, "[Marker Infos]")?;
+    writeln!(vmrk, "; Each entry: Mk<Marker number>=<Type>,<Description>,<Position in data points>,")?;
+    writeln!(vmrk, "; <Size in data points>, <Channel number (0 = marker is related to all channels)>")?;
+    let date = rec.info.start.format("%Y%m%d%H%M%S%6f");
+    writeln!(vmrk, "Mk1=New Segment,,1,1,0,{}", date)?;
+    let mut n = 2;
+    for (sample, code) in rec.events() {
+        // positions are 1-based
+        writeln!(vmrk, "Mk{}=Stimulus,S{:>3},{},1,0", n, code, sample + 1)?;
+        n += 1;
+    }
+    let fs = rec.info.sampling_rate as f64;
+    for annot in rec.annotations.iter() {
+        let pos = (annot.onset * fs).round() as usize + 1;
+        if annot.label == BOUNDARY {
+            let description = escape(&annot.description);
+            writeln!(vmrk, "Mk{}=New Segment,{},{},1,0", n, description, pos)?;
+        } else {
+            let size = ((annot.duration * fs).round() as usize).max(1);
+            writeln!(vmrk, "Mk{}=Comment,{},{},{},0", n, escape(&annot.text()), pos, size)?;
+        }
+        n += 1;
+    }
+    vmrk.flush()?;
+
+    // multiplexed data
+    let mut eeg = BufWriter::new(File::create(&eeg_path)?);
+    let mut channels: Vec<_> = (0..rec.channels.len()).map(|ch| rec.channel_uv(ch)).collect();
+    for _ in 0..rec.len() {
+        for channel in channels.iter_mut() {
+            let v = channel.next().unwrap_or(0.0) as f32;
+            eeg.write_all(&v.to_le_bytes())?;
+        }
+    }
+    eeg.flush()
+}
+
+/// Reads a BrainVision data set, `path` being its header file. The sampling
+/// rate and channel labels are taken from the header and the start time from
+/// the first `New Segment` marker, the rest of `info` is kept. Only
+/// multiplexed `IEEE_FLOAT_32` and `INT_16` data is supported.
+///
+/// Stimulus markers become marks, later `New Segment` markers become boundary
+/// annotations and the rest of the markers, annotations. The format has no
+/// lead-off status, all electrodes are read as connected.
+pub fn read_brainvision(path: &Path, mut info: RecordingInfo) -> io::Result<Recording> {
+    let invalid = |msg: String| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), msg))
+    };
+
+    let vhdr = fs::read_to_string(path)?;
+    let header = sections(&vhdr);
+    let common = header.get("Common Infos").ok_or_else(|| invalid("no [Common Infos]".into()))?;
+    let value = |key: &str| common.get(key).map(String::as_str).unwrap_or_default();
+
+    if !matches!(value("DataOrientation"), "" | "MULTIPLEXED") {
+        return Err(invalid("only multiplexed data is supported".into()));
+    }
+    let num_ch: usize = value("NumberOfChannels")
+        .parse()
+        .map_err(|_| invalid("invalid NumberOfChannels".into()))?;
+    let interval: f64 = value("SamplingInterval")
+        .parse()
+        .map_err(|_| invalid("invalid SamplingInterval".into()))?;
+    let format = header
+        .get("Binary Infos")
+        .and_then(|b| b.get("BinaryFormat"))
+        .map_or("", String::as_str);
+    let sample_size = match format {
+        "IEEE_FLOAT_32" => 4,
+        "INT_16" => 2,
+        _ => return Err(invalid(format!("unsupported binary format {}", format))),
+    };
+
+    // channel labels and the normalized value of a unit of each channel
+    let mut labels = vec![];
+    let mut scales = vec![];
+    let channel_infos = header.get("Channel Infos");
+    for i in 1..=num_ch {
+        let entry = channel_infos.and_then(|c| c.get(&format!("Ch{}", i)));
+        let fields: Vec<&str> = entry.map_or(vec![], |e| e.split(',').collect());
+        labels.push(fields.first().map_or(format!("Ch{}", i), |l| unescape(l)));
+        let resolution: f64 = fields.get(2).and_then(|r| r.parse().ok()).unwrap_or(1.0);
+        let unit = match fields.get(3).copied() {
+            Some("mV") => 1e3,
+            Some("V") => 1e6,
+            Some("nV") => 1e-3,
+            _ => 1.0,
+        };
+        scales.push(resolution * unit / FULL_SCALE_UV);
+    }
+    info.sampling_rate = (1e6 / interval).round() as u32;
+    info.labels = labels;
+
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let data = fs::read(dir.join(value("DataFile")))?;
+    let len = data.len() / (sample_size * num_ch.max(1));
+    let mut channels = vec![Vec::with_capacity(len); num_ch];
+    for (i, bytes) in data.chunks_exact(sample_size).take(len * num_ch).enumerate() {
+        let v = match bytes {
+            [a, b, c, d] => f32::from_le_bytes([*a, *b, *c, *d]) as f64,
+            [a, b] => i16::from_le_bytes([*a, *b]) as f64,
+            _ => unreachable!(),
+        };
+        channels[i % num_ch].push((v * scales[i % num_ch]) as f32);
+    }
+
+    let mut rec = Recording {
+        info,
+        channels,
+        status: vec![vec![true; len]; num_ch],
+        marks: vec![0; len],
+        times: vec![],
+        annotations: vec![],
+    };
+
+    let vmrk = match value("MarkerFile") {
+        "" => return Ok(rec),
+        name => fs::read_to_string(dir.join(name))?,
+    };
+    let fs = rec.info.sampling_rate as f64;
+    let sections = sections(&vmrk);
+    let mut markers: Vec<(usize, &String)> = sections
+        .get("Marker Infos")
+        .into_iter()
+        .flatten()
+        .filter_map(|(k, v)| Some((k.strip_prefix("Mk")?.parse().ok()?, v)))
+        .collect();
+    markers.sort();
+
+    for (n, marker) in markers {
+        let fields: Vec<&str> = marker.split(',').collect();
+        let Some(pos) = fields.get(2).and_then(|p| p.parse::<usize>().ok()) else {
+            continue;
+        };
+        let description = fields.get(1).map_or(String::new(), |d| unescape(d));
+        let size: usize = fields.get(3).and_then(|s| s.parse().ok()).unwrap_or(1);
+        // positions are 1-based
+        let sample = pos.saturating_sub(1);
+        let onset = sample as f64 / fs;
+
+        match fields[0] {
+            "New Segment" if n == 1 => {
+                let date = fields
+                    .get(5)
+                    .and_then(|d| NaiveDateTime::parse_from_str(d, "%Y%m%d%H%M%S%6f").ok());
+                if let Some(start) = date.and_then(|d| Local.from_local_datetime(&d).earliest()) {
+                    rec.info.start = start;
+                }
+            }
+            "New Segment" => rec.annotations.push(Annotation {
+                onset,
+                duration: 0.0,
+                label: BOUNDARY.into(),
+                description,
+            }),
+            "Stimulus" if sample < len => {
+                let code = description.trim_start_matches('S').trim().parse().unwrap_or(0);
+                rec.marks[sample] = code;
+            }
+            _ => {
+                let (label, description) = Annotation::split_text(&description);
+                rec.annotations.push(Annotation {
+                    onset,
+                    duration: if size > 1 { size as f64 / fs } else { 0.0 },
+                    label: label.into(),
+                    description: description.into(),
+                })
+            }
+        }
+    }
+
+    Ok(rec)
+}
+
+/// Splits an INI-like header or marker file into its `[sections]` of
+/// `key=value` entries. Comments start with `;`.
+fn sections(text: &str) -> HashMap<String, HashMap<String, String>> {
+    let mut sections: HashMap<String, HashMap<String, String>> = HashMap::new();
+    let mut current = String::new();
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with(';') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            current = name.to_string();
+        } else if let Some((key, value)) = line.split_once('=') {
+            sections.entry(current.clone()).or_default().insert(key.into(), value.into());
+        }
+    }
+    sections
+}
+
+/// Commas separate the fields of an entry, they are written as `\1`.
+fn escape(s: &str) -> String {
+    s.replace(',', "\\1")
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("\\1", ",")
+}
@@ -0,0 +1,149 @@
+//! Transformations applied to recordings when converting them to another
+//! format: channel selection, filtering and resampling.
+
+use biquad::*;
+
+use super::Recording;
+
+/// Q of the notch filters, about 2 Hz wide at 50 Hz.
+const NOTCH_Q: f32 = 25.0;
+
+/// Cutoff of the anti-aliasing filter when downsampling, relative to the new
+/// sampling rate.
+const ANTI_ALIAS_CUTOFF: f32 = 0.4;
+
+#[derive(Clone, Default)]
+pub struct ConvertOptions {
+    /// Indexes of the channels to keep, in the output order. `None` keeps all.
+    pub channels: Option<Vec<usize>>,
+    /// Cutoff frequencies (Hz) of the Butterworth high-pass and low-pass filters.
+    pub highpass: Option<f32>,
+    pub lowpass: Option<f32>,
+    /// Frequency (Hz) of the power line noise to remove with a notch filter.
+    pub notch: Option<f32>,
+    /// New sampling rate.
+    pub sampling_rate: Option<u32>,
+}
+
+/// Applies the options to a recording, in the order channel selection,
+/// filters, resampling. The filters are causal, like the ones of the live
+/// plots, and are described in the prefilter field of the recording info.
+pub fn convert(mut rec: Recording, opts: &ConvertOptions) -> Result<Recording, String> {
+    if let Some(channels) = &opts.channels {
+        select_channels(&mut rec, channels)?;
+    }
+
+    let fs = rec.info.sampling_rate as f32;
+    let mut filters = vec![];
+    let mut description = vec![];
+    if let Some(f) = opts.highpass {
+        filters.push(coefficients(Type::HighPass, fs, f, Q_BUTTERWORTH_F32)?);
+        description.push(format!("HP:{}Hz", f));
+    }
+    if let Some(f) = opts.lowpass {
+        filters.push(coefficients(Type::LowPass, fs, f, Q_BUTTERWORTH_F32)?);
+        description.push(format!("LP:{}Hz", f));
+    }
+    if let Some(f) = opts.notch {
+        filters.push(coefficients(Type::Notch, fs, f, NOTCH_Q)?);
+        description.push(format!("N:{}Hz", f));
+    }
+    if !filters.is_empty() {
+        for channel in rec.channels.iter_mut() {
+            filter(channel, &filters);
+        }
+        rec.info.prefilter = [rec.info.prefilter.clone()]
+            .into_iter()
+            .chain(description)
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ");
+    }
+
+    if let Some(rate) = opts.sampling_rate {
+        if rate == 0 {
+            return Err("The sampling rate must be positive".into());
+        }
+        if rate != rec.info.sampling_rate {
+            resample(&mut rec, rate)?;
+        }
+    }
+    Ok(rec)
+}
+
+/// Keeps only the given channels, in the given order.
+fn select_channels(rec: &mut Recording, channels: &[usize]) -> Result<(), String> {
+    if let Some(ch) = channels.iter().find(|ch| **ch >= rec.channels.len()) {
+        return Err(format!("There is no channel {}", ch + 1));
+    }
+    rec.channels = channels.iter().map(|ch| rec.channels[*ch].clone()).collect();
+    rec.status = channels.iter().map(|ch| rec.status[*ch].clone()).collect();
+    rec.info.labels = channels
+        .iter()
+        .map(|ch| rec.info.labels.get(*ch).cloned().unwrap_or_else(|| format!("CH-{}", ch + 1)))
+        .collect();
+    Ok(())
+}
+
+fn coefficients(kind: Type<f32>, fs: f32, f0: f32, q: f32) -> Result<Coefficients<f32>, String> {
+    Coefficients::<f32>::from_params(kind, fs.hz(), f0.hz(), q)
+        .map_err(|_| format!("Invalid filter frequency {} Hz for a sampling rate of {} Hz", f0, fs))
+}
+
+fn filter(channel: &mut [f32], coeffs: &[Coefficients<f32>]) {
+    for coeffs in coeffs {
+        let mut filter = DirectForm1::<f32>::new(*coeffs);
+        for v in channel.iter_mut() {
+            *v = filter.run(*v);
+        }
+    }
+}
+
+/// Resamples the channels by linear interpolation, low-pass filtering them
+/// first when downsampling. Lead-off status and host times take the value of
+/// the nearest sample, and every mark is kept, moved to the nearest sample.
+fn resample(rec: &mut Recording, rate: u32) -> Result<(), String> {
+    let old_rate = rec.info.sampling_rate;
+    let ratio = old_rate as f64 / rate as f64;
+    let len = (rec.len() as f64 / ratio).round() as usize;
+
+    if rate < old_rate {
+        let cutoff = ANTI_ALIAS_CUTOFF * rate as f32;
+        let anti_alias = coefficients(Type::LowPass, old_rate as f32, cutoff, Q_BUTTERWORTH_F32)?;
+        for channel in rec.channels.iter_mut() {
+            // two passes for a steeper slope
+            filter(channel, &[anti_alias, anti_alias]);
+        }
+    }
+
+    let last = rec.len().saturating_sub(1);
+    let nearest = |j: usize| ((j as f64 * ratio).round() as usize).min(last);
+    for channel in rec.channels.iter_mut() {
+        *channel = (0..len)
+            .map(|j| {
+                let t = j as f64 * ratio;
+                let i = (t.floor() as usize).min(last);
+                let next = (i + 1).min(last);
+                let frac = (t - i as f64) as f32;
+                channel[i] + (channel[next] - channel[i]) * frac
+            })
+            .collect();
+    }
+    for status in rec.status.iter_mut() {
+        *status = (0..len).map(|j| status[nearest(j)]).collect();
+    }
+    if !rec.times.is_empty() {
+        rec.times = (0..len).map(|j| rec.times[nearest(j)]).collect();
+    }
+
+    let mut marks = vec![0; len];
+    if len > 0 {
+        for (i, code) in rec.events() {
+            let j = ((i as f64 / ratio).round() as usize).min(len - 1);
+            marks[j] = code;
+        }
+    }
+    rec.marks = marks;
+    rec.info.sampling_rate = rate;
+    Ok(())
+}
@@ -0,0 +1,179 @@
+//! The CSV format of NiGUI. Rows always carry the samples of every channel
+//! (`channel-<i>`), and can carry the lead-off status, the mark, the sample
+//! index and the host time at which the sample was received. Which of those
+//! columns are written, the delimiter and the precision of the samples are
+//! set with `CsvOptions`.
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use super::{Recording, RecordingInfo};
+
+/// Optional columns of a CSV recording. The channel columns are always
+/// written, so that the recording can be read back.
+#[derive(Clone, PartialEq)]
+pub struct CsvColumns {
+    /// Sample index, starting at 0 (`sample`)
+    pub sample: bool,
+    /// Host wall-clock time in µs since the Unix epoch (`timestamp_us`)
+    pub timestamp: bool,
+    /// Seconds since the start of the recording, from the host clock (`elapsed_s`)
+    pub elapsed: bool,
+    /// Lead-off status of each channel, 1 if connected (`status ch-<i>`)
+    pub status: bool,
+    /// Mark code (`mark`)
+    pub mark: bool,
+}
+
+#[derive(Clone, PartialEq)]
+pub struct CsvOptions {
+    pub delimiter: char,
+    /// Number of decimals of the samples. `None` writes the shortest
+    /// representation that reads back to the same value.
+    pub precision: Option<usize>,
+    pub columns: CsvColumns,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        Self {
+            delimiter: ',',
+            precision: None,
+            columns: CsvColumns {
+                sample: true,
+                timestamp: true,
+                elapsed: true,
+                status: true,
+                mark: true,
+            },
+        }
+    }
+}
+
+/// Writes the recording as a CSV file.
+pub fn write_csv(path: &Path, rec: &Recording, opts: &CsvOptions) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    write_header(&mut out, rec.channels.len(), opts)?;
+    write_rows(&mut out, rec, rec.info.first_sample, opts)?;
+    out.flush()
+}
+
+/// Writes the header line of a CSV recording with `num_ch` channels.
+pub fn write_header(out: &mut impl Write, num_ch: usize, opts: &CsvOptions) -> io::Result<()> {
+    let cols = &opts.columns;
+    let mut header: Vec<String> = vec![];
+    if cols.sample {
+        header.push("sample".into());
+    }
+    if cols.timestamp {
+        header.push("timestamp_us".into());
+    }
+    if cols.elapsed {
+        header.push("elapsed_s".into());
+    }
+    header.extend((0..num_ch).map(|i| format!("channel-{}", i)));
+    if cols.status {
+        header.extend((0..num_ch).map(|i| format!("status ch-{}", i)));
+    }
+    if cols.mark {
+        header.push("mark".into());
+    }
+    writeln!(out, "{}", header.join(&opts.delimiter.to_string()))
+}
+
+/// Writes the rows of `rec`. `first_sample` is the index of its first sample
+/// in the whole recording, `rec` may be a chunk of a longer recording.
+pub fn write_rows(
+    out: &mut impl Write,
+    rec: &Recording,
+    first_sample: usize,
+    opts: &CsvOptions,
+) -> io::Result<()> {
+    let cols = &opts.columns;
+    let start_us = rec.info.start.timestamp_micros();
+    let delimiter = opts.delimiter.to_string();
+    let sample_str = |v: f32| match opts.precision {
+        Some(p) => format!("{:.*}", p, v),
+        None => v.to_string(),
+    };
+
+    let mut row: Vec<String> = vec![];
+    for j in 0..rec.len() {
+        row.clear();
+        let timestamp = rec.timestamp_us(j);
+        if cols.sample {
+            row.push((first_sample + j).to_string());
+        }
+        if cols.timestamp {
+            row.push(timestamp.to_string());
+        }
+        if cols.elapsed {
+            row.push(format!("{:.6}", (timestamp - start_us) as f64 / 1e6));
+        }
+        row.extend(rec.channels.iter().map(|ch| sample_str(ch[j])));
+        if cols.status {
+            row.extend(rec.status.iter().map(|st| (st[j] as u8).to_string()));
+        }
+        if cols.mark {
+            row.push(rec.marks[j].to_string());
+        }
+        writeln!(out, "{}", row.join(&delimiter))?;
+    }
+    Ok(())
+}
+
+/// Reads a CSV recording. Columns are looked up by name and the delimiter is
+/// guessed from the header, so files written with any `CsvOptions` can be
+/// read.
+pub fn read_csv(path: &Path, info: RecordingInfo) -> io::Result<Recording> {
+    let text = fs::read_to_string(path)?;
+    let mut lines = text.lines();
+    let header_line = lines.next().unwrap_or_default();
+    let delimiter = [',', ';', '\t']
+        .into_iter()
+        .find(|d| header_line.contains(*d))
+        .unwrap_or(',');
+    let header: Vec<&str> = header_line.split(delimiter).collect();
+
+    let column = |name: &str| header.iter().position(|h| h.trim() == name);
+    let num_ch = (0..).take_while(|i| column(&format!("channel-{}", i)).is_some()).count();
+    let mut indices = vec![];
+    for i in 0..num_ch {
+        indices.push(column(&format!("channel-{}", i)));
+    }
+    for i in 0..num_ch {
+        indices.push(column(&format!("status ch-{}", i)));
+    }
+    indices.push(column("mark"));
+    let timestamp_idx = column("timestamp_us");
+
+    let invalid = |n: usize| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: invalid value in line {}", path.display(), n + 2),
+        )
+    };
+
+    let mut bufs = vec![vec![]; indices.len()];
+    let mut times = vec![];
+    for (n, line) in lines.enumerate().filter(|(_, l)| !l.trim().is_empty()) {
+        let values: Vec<&str> = line.split(delimiter).collect();
+        for (k, (buf, idx)) in bufs.iter_mut().zip(indices.iter()).enumerate() {
+            // missing status columns mean connected electrodes, missing marks no mark
+            let v = match idx {
+                Some(idx) => values.get(*idx).and_then(|v| v.trim().parse().ok()),
+                None => Some(if k < 2 * num_ch { 1.0 } else { 0.0 }),
+            };
+            buf.push(v.ok_or_else(|| invalid(n))?);
+        }
+        if let Some(idx) = timestamp_idx {
+            let t = values.get(idx).and_then(|v| v.trim().parse().ok());
+            times.push(t.ok_or_else(|| invalid(n))?);
+        }
+    }
+
+    let mut rec = Recording::from_buffs(&bufs, info);
+    rec.times = times;
+    Ok(rec)
+}
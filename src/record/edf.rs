@@ -0,0 +1,520 @@
+//! EDF+ and BDF readers and writers. See <https://www.edfplus.info/specs/edfplus.html>
+//! and <https://www.biosemi.com/faq/file_format.htm>.
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use chrono::prelude::*;
+
+use super::{Annotation, Recording, RecordingInfo, BOUNDARY};
+use crate::wave::FULL_SCALE_UV;
+use crate::wifi::{counts_to_float, float_to_counts, ADC_MAX, ADC_MIN};
+
+const DIGITAL_MIN: i32 = -32768;
+const DIGITAL_MAX: i32 = 32767;
+
+/// Header fields of a signal.
+struct Signal {
+    label: String,
+    transducer: String,
+    dimension: String,
+    phys_min: String,
+    phys_max: String,
+    dig_min: i32,
+    dig_max: i32,
+    prefilter: String,
+    samples: usize,
+}
+
+/// Writes the recording as an EDF+ file with one signal per channel and an
+/// `EDF Annotations` signal holding the marks and the annotations. Data
+/// records last one second.
+///
+/// The physical range of each channel is taken from its data, to make the
+/// most of the 16 bits of EDF.
+pub fn write_edf(path: &Path, rec: &Recording) -> io::Result<()> {
+    let fs = rec.info.sampling_rate as usize;
+    let num_records = rec.len().div_ceil(fs).max(1);
+
+    // the TALs of each data record: the mandatory time-keeping TAL followed
+    // by the annotations of the marks that fall into the record
+    let mut tals: Vec<Vec<u8>> = (0..num_records)
+        .map(|r| format!("+{}\x14\x14\0", r).into_bytes())
+        .collect();
+    for (sample, code) in rec.events() {
+        let onset = sample as f64 / fs as f64;
+        let tal = format!("+{}\x14{}\x14\0", onset, rec.info.mark_label(code));
+        tals[sample / fs].extend_from_slice(tal.as_bytes());
+    }
+    for annot in rec.annotations.iter() {
+        let text = annot.text();
+        let tal = if annot.duration > 0.0 {
+            format!("+{}\x15{}\x14{}\x14\0", annot.onset, annot.duration, text)
+        } else {
+            format!("+{}\x14{}\x14\0", annot.onset, text)
+        };
+        let r = (annot.onset.max(0.0) as usize).min(num_records - 1);
+        tals[r].extend_from_slice(tal.as_bytes());
+    }
+    let annot_samples = tals.iter().map(|t| t.len()).max().unwrap_or(0).div_ceil(2);
+
+    let ranges: Vec<(f64, f64)> = (0..rec.channels.len())
+        .map(|ch| physical_range(rec, ch))
+        .collect();
+    let mut signals: Vec<Signal> = ranges
+        .iter()
+        .zip(rec.info.labels.iter())
+        .map(|((min, max), label)| Signal {
+            label: label.clone(),
+            transducer: "AgAgCl electrode".into(),
+            dimension: "uV".into(),
+            phys_min: min.to_string(),
+            phys_max: max.to_string(),
+            dig_min: DIGITAL_MIN,
+            dig_max: DIGITAL_MAX,
+            prefilter: rec.info.prefilter.clone(),
+            samples: fs,
+        })
+        .collect();
+    signals.push(Signal {
+        label: "EDF Annotations".into(),
+        transducer: String::new(),
+        dimension: String::new(),
+        phys_min: "-1".into(),
+        phys_max: "1".into(),
+        dig_min: DIGITAL_MIN,
+        dig_max: DIGITAL_MAX,
+        prefilter: String::new(),
+        samples: annot_samples,
+    });
+
+    let mut out = BufWriter::new(File::create(path)?);
+    // pauses make the recording discontinuous
+    let paused = rec.annotations.iter().any(|a| a.label == BOUNDARY);
+    let reserved = if paused { "EDF+D" } else { "EDF+C" };
+    write_header(&mut out, rec, b"0       ", reserved, num_records, &signals)?;
+
+    // one iterator per channel, consumed a record at a time
+    let mut channels: Vec<_> = (0..rec.channels.len()).map(|ch| rec.channel_uv(ch)).collect();
+    for tal in tals.iter() {
+        for (samples, &(min, max)) in channels.iter_mut().zip(ranges.iter()) {
+            for _ in 0..fs {
+                let d = to_digital(samples.next().unwrap_or(0.0), min, max);
+                out.write_all(&(d as i16).to_le_bytes())?;
+            }
+        }
+        out.write_all(tal)?;
+        out.write_all(&vec![0u8; 2 * annot_samples - tal.len()])?;
+    }
+
+    out.flush()
+}
+
+/// Writes the recording as a BioSemi BDF file. Channels are stored as the
+/// original 24-bit ADC counts, scaled to µV through the physical range.
+///
+/// A last `Status` channel carries, for each sample, the mark code in bits
+/// 0-7 and the lead-off state of each channel from bit 8 onwards (bit
+/// `8 + ch` is set when the electrode of channel `ch` is disconnected).
+pub fn write_bdf(path: &Path, rec: &Recording) -> io::Result<()> {
+    let fs = rec.info.sampling_rate as usize;
+    let num_records = rec.len().div_ceil(fs).max(1);
+
+    let mut signals: Vec<Signal> = rec
+        .info
+        .labels
+        .iter()
+        .take(rec.channels.len())
+        .map(|label| Signal {
+            label: label.clone(),
+            transducer: "AgAgCl electrode".into(),
+            dimension: "uV".into(),
+            phys_min: (-FULL_SCALE_UV).to_string(),
+            phys_max: FULL_SCALE_UV.to_string(),
+            dig_min: ADC_MIN,
+            dig_max: ADC_MAX,
+            prefilter: rec.info.prefilter.clone(),
+            samples: fs,
+        })
+        .collect();
+    signals.push(Signal {
+        label: "Status".into(),
+        transducer: "Triggers and Status".into(),
+        dimension: "Boolean".into(),
+        phys_min: ADC_MIN.to_string(),
+        phys_max: ADC_MAX.to_string(),
+        dig_min: ADC_MIN,
+        dig_max: ADC_MAX,
+        prefilter: "No filtering".into(),
+        samples: fs,
+    });
+
+    let mut out = BufWriter::new(File::create(path)?);
+    write_header(&mut out, rec, b"\xffBIOSEMI", "24BIT", num_records, &signals)?;
+
+    let write_24bit = |out: &mut BufWriter<File>, v: i32| out.write_all(&v.to_le_bytes()[..3]);
+    for r in 0..num_records {
+        for channel in rec.channels.iter() {
+            for s in (r * fs)..((r + 1) * fs) {
+                write_24bit(&mut out, channel.get(s).map_or(0, |v| float_to_counts(*v)))?;
+            }
+        }
+        for s in (r * fs)..((r + 1) * fs) {
+            let mut status = rec.marks.get(s).copied().unwrap_or(0) as i32;
+            for (ch, ch_status) in rec.status.iter().enumerate().take(16) {
+                if !ch_status.get(s).copied().unwrap_or(true) {
+                    status |= 1 << (8 + ch);
+                }
+            }
+            write_24bit(&mut out, status)?;
+        }
+    }
+
+    out.flush()
+}
+
+/// Writes the main header followed by the signal headers.
+fn write_header(
+    out: &mut impl Write,
+    rec: &Recording,
+    version: &[u8; 8],
+    reserved: &str,
+    num_records: usize,
+    signals: &[Signal],
+) -> io::Result<()> {
+    let subject = if rec.info.subject.is_empty() {
+        "X".to_string()
+    } else {
+        rec.info.subject.replace(' ', "_")
+    };
+    let start = rec.info.start;
+    let startdate = start.format("%d-%b-%Y").to_string().to_uppercase();
+
+    out.write_all(version)?;
+    field(out, &format!("{} X X X", subject), 80)?;
+    field(out, &format!("Startdate {} X X NAPSE", startdate), 80)?;
+    field(out, &start.format("%d.%m.%y").to_string(), 8)?;
+    field(out, &start.format("%H.%M.%S").to_string(), 8)?;
+    field(out, &(256 * (signals.len() + 1)).to_string(), 8)?;
+    field(out, reserved, 44)?;
+    field(out, &num_records.to_string(), 8)?;
+    field(out, "1", 8)?;
+    field(out, &signals.len().to_string(), 4)?;
+
+    // signal headers are stored field by field: the labels of all the
+    // signals, then all the transducer types, and so on
+    for s in signals {
+        field(out, &s.label, 16)?;
+    }
+    for s in signals {
+        field(out, &s.transducer, 80)?;
+    }
+    for s in signals {
+        field(out, &s.dimension, 8)?;
+    }
+    for s in signals {
+        field(out, &s.phys_min, 8)?;
+    }
+    for s in signals {
+        field(out, &s.phys_max, 8)?;
+    }
+    for s in signals {
+        field(out, &s.dig_min.to_string(), 8)?;
+    }
+    for s in signals {
+        field(out, &s.dig_max.to_string(), 8)?;
+    }
+    for s in signals {
+        field(out, &s.prefilter, 80)?;
+    }
+    for s in signals {
+        field(out, &s.samples.to_string(), 8)?;
+    }
+    for _ in signals {
+        field(out, "", 32)?;
+    }
+    Ok(())
+}
+
+/// Returns the physical range (µV) of a channel, rounded outwards to whole
+/// µV so that it fits the 8 characters of the header fields.
+fn physical_range(rec: &Recording, ch: usize) -> (f64, f64) {
+    let (min, max) = rec
+        .channel_uv(ch)
+        .fold((f64::MAX, f64::MIN), |(min, max), v| (min.min(v), max.max(v)));
+    if min > max {
+        return (-1.0, 1.0); // empty channel
+    }
+    let (min, max) = (min.floor(), max.ceil());
+    if min == max { (min - 1.0, max + 1.0) } else { (min, max) }
+}
+
+/// Maps a physical value (µV) linearly from `min..max` to the digital range.
+fn to_digital(v: f64, min: f64, max: f64) -> i32 {
+    let range = (DIGITAL_MAX - DIGITAL_MIN) as f64;
+    let d = (v - min) / (max - min) * range + DIGITAL_MIN as f64;
+    (d.round() as i32).clamp(DIGITAL_MIN, DIGITAL_MAX)
+}
+
+/// Writes an ASCII header field, padded with spaces to `len` bytes.
+fn field(out: &mut impl Write, value: &str, len: usize) -> io::Result<()> {
+    let mut bytes: Vec<u8> = value
+        .chars()
+        .map(|c| if c.is_ascii() && !c.is_ascii_control() { c as u8 } else { b'_' })
+        .take(len)
+        .collect();
+    bytes.resize(len, b' ');
+    out.write_all(&bytes)
+}
+
+/// Reads an EDF, EDF+ or BDF file. The start time, sampling rate and channel
+/// labels are taken from the header, the rest of `info` is kept.
+///
+/// Annotations named after a mark (see `RecordingInfo::mark_code`) become
+/// marks, the rest are returned as annotations. In BDF files, the marks and
+/// the lead-off status are read from the `Status` channel. Since data records
+/// are complete, the recording may end with up to one record of padding.
+pub fn read_edf(path: &Path, mut info: RecordingInfo) -> io::Result<Recording> {
+    let data = fs::read(path)?;
+    let invalid = |msg: &str| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), msg))
+    };
+    let text = |start: usize, len: usize| -> io::Result<&str> {
+        let bytes = data.get(start..start + len).ok_or_else(|| invalid("truncated header"))?;
+        std::str::from_utf8(bytes).map(str::trim).map_err(|_| invalid("invalid header"))
+    };
+    let number = |start: usize, len: usize| -> io::Result<f64> {
+        text(start, len)?.parse().map_err(|_| invalid("invalid header"))
+    };
+
+    let bdf = data.first() == Some(&0xff);
+    let sample_size = if bdf { 3 } else { 2 };
+    let header_len = number(184, 8)? as usize;
+    let record_duration = number(244, 8)?;
+    let ns = number(252, 4)? as usize;
+    if header_len != 256 * (ns + 1) || record_duration <= 0.0 {
+        return Err(invalid("invalid header"));
+    }
+
+    // signal headers, stored field by field
+    let field_offsets = [16, 80, 8, 8, 8, 8, 8, 80, 8, 32];
+    let field = |f: usize, i: usize| {
+        let offset: usize = field_offsets[..f].iter().map(|len| len * ns).sum();
+        256 + offset + i * field_offsets[f]
+    };
+    let mut signals = vec![];
+    for i in 0..ns {
+        signals.push(Signal {
+            label: text(field(0, i), 16)?.to_string(),
+            transducer: text(field(1, i), 80)?.to_string(),
+            dimension: text(field(2, i), 8)?.to_string(),
+            phys_min: text(field(3, i), 8)?.to_string(),
+            phys_max: text(field(4, i), 8)?.to_string(),
+            dig_min: number(field(5, i), 8)? as i32,
+            dig_max: number(field(6, i), 8)? as i32,
+            prefilter: text(field(7, i), 80)?.to_string(),
+            samples: number(field(8, i), 8)? as usize,
+        });
+    }
+    if signals.iter().any(|s| s.samples > data.len()) {
+        return Err(invalid("truncated"));
+    }
+    let record_size: usize = signals.iter().map(|s| s.samples * sample_size).sum();
+    if record_size == 0 {
+        return Err(invalid("empty data records"));
+    }
+    // the number of records may be -1 (unknown) in files that were not closed
+    let data_len = data.len().checked_sub(header_len).ok_or_else(|| invalid("truncated"))?;
+    let num_records = data_len / record_size;
+
+    let is_annotations = |s: &Signal| s.label == "EDF Annotations";
+    let is_status = |s: &Signal| bdf && s.label == "Status";
+    let channels: Vec<usize> = (0..ns)
+        .filter(|&i| !is_annotations(&signals[i]) && !is_status(&signals[i]))
+        .collect();
+    let fs = channels.first().map_or(0, |&i| signals[i].samples);
+    if channels.iter().any(|&i| signals[i].samples != fs) {
+        return Err(invalid("channels with different sampling rates are not supported"));
+    }
+    // the marks and lead-off status are read sample by sample
+    if signals.iter().any(|s| is_status(s) && s.samples != fs) {
+        return Err(invalid("the Status signal has a different sampling rate than the channels"));
+    }
+    let sampling_rate = fs as f64 / record_duration;
+    info.sampling_rate = sampling_rate.round() as u32;
+    info.labels = channels.iter().map(|&i| signals[i].label.clone()).collect();
+    if let Some(prefilter) = channels.first().map(|&i| &signals[i].prefilter) {
+        info.prefilter = prefilter.clone();
+    }
+    if let Some(start) = start_time(text(168, 8)?, text(176, 8)?) {
+        info.start = start;
+    }
+
+    let len = num_records * fs;
+    let mut rec = Recording {
+        info,
+        channels: vec![Vec::with_capacity(len); channels.len()],
+        status: vec![vec![true; len]; channels.len()],
+        marks: vec![0; len],
+        times: vec![],
+        annotations: vec![],
+    };
+
+    for r in 0..num_records {
+        let mut offset = header_len + r * record_size;
+        for (i, signal) in signals.iter().enumerate() {
+            let bytes = &data[offset..offset + signal.samples * sample_size];
+            offset += bytes.len();
+            if is_annotations(signal) {
+                read_tals(bytes, &mut rec, sampling_rate);
+                continue;
+            }
+
+            let samples = bytes.chunks_exact(sample_size).map(|b| match b {
+                [a, b, c] => i32::from_le_bytes([0, *a, *b, *c]) >> 8,
+                [a, b] => i16::from_le_bytes([*a, *b]) as i32,
+                _ => unreachable!(),
+            });
+            if is_status(signal) {
+                for (s, v) in samples.enumerate().map(|(s, v)| (r * fs + s, v)) {
+                    rec.marks[s] = (v & 0xff) as u8;
+                    // the lead-off status of the first 16 channels fits in the Status signal
+                    for (ch, status) in rec.status.iter_mut().enumerate().take(16) {
+                        status[s] = v & (1 << (8 + ch)) == 0;
+                    }
+                }
+            } else {
+                let ch = channels.iter().position(|&c| c == i).unwrap();
+                let to_float = physical_scale(signal, bdf)
+                    .ok_or_else(|| invalid("invalid physical or digital range"))?;
+                rec.channels[ch].extend(samples.map(to_float));
+            }
+        }
+    }
+
+    Ok(rec)
+}
+
+/// Returns the conversion of the digital values of a signal to the
+/// normalized samples of `Recording`. BDF channels written by NiGUI hold raw
+/// ADC counts, those are converted exactly.
+fn physical_scale(signal: &Signal, bdf: bool) -> Option<Box<dyn Fn(i32) -> f32>> {
+    let phys_min: f64 = signal.phys_min.parse().ok()?;
+    let phys_max: f64 = signal.phys_max.parse().ok()?;
+    let (dig_min, dig_max) = (signal.dig_min as f64, signal.dig_max as f64);
+    if phys_max == phys_min || dig_max == dig_min {
+        return None;
+    }
+    let raw_counts = signal.dig_min == ADC_MIN
+        && signal.dig_max == ADC_MAX
+        && phys_min == -FULL_SCALE_UV
+        && phys_max == FULL_SCALE_UV;
+    if bdf && raw_counts {
+        return Some(Box::new(counts_to_float));
+    }
+
+    let unit = match signal.dimension.as_str() {
+        "mV" => 1e3,
+        "V" => 1e6,
+        _ => 1.0,
+    };
+    let gain = (phys_max - phys_min) / (dig_max - dig_min) * unit / FULL_SCALE_UV;
+    let offset = phys_min * unit / FULL_SCALE_UV - dig_min * gain;
+    Some(Box::new(move |d| (d as f64 * gain + offset) as f32))
+}
+
+/// Parses the TALs of the annotations signal of a data record. The first
+/// TAL of each record only keeps the time and is skipped.
+fn read_tals(bytes: &[u8], rec: &mut Recording, sampling_rate: f64) {
+    let text = String::from_utf8_lossy(bytes);
+    for tal in text.split('\0').filter(|t| !t.is_empty()) {
+        let mut parts = tal.split('\x14');
+        let time = parts.next().unwrap_or_default();
+        let (onset, duration) = match time.split_once('\x15') {
+            Some((onset, duration)) => (onset, duration.parse().unwrap_or(0.0)),
+            None => (time, 0.0),
+        };
+        let Ok(onset) = onset.parse::<f64>() else { continue };
+
+        for text in parts.filter(|l| !l.is_empty()) {
+            let sample = (onset * sampling_rate).round() as usize;
+            let (label, description) = Annotation::split_text(text);
+            match rec.info.mark_code(label) {
+                Some(code) if duration == 0.0 && sample < rec.marks.len() => rec.marks[sample] = code,
+                _ => rec.annotations.push(Annotation {
+                    onset,
+                    duration,
+                    label: label.to_string(),
+                    description: description.to_string(),
+                }),
+            }
+        }
+    }
+}
+
+/// Parses the `dd.mm.yy` and `hh.mm.ss` start fields of the header.
+fn start_time(date: &str, time: &str) -> Option<DateTime<Local>> {
+    let date = NaiveDate::parse_from_str(date, "%d.%m.%y").ok()?;
+    let time = NaiveTime::parse_from_str(time, "%H.%M.%S").ok()?;
+    Local.from_local_datetime(&date.and_time(time)).earliest()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recording(len: usize, num_ch: usize) -> Recording {
+        let mut bufs: Vec<Vec<f32>> = (0..num_ch)
+            .map(|ch| {
+                (0..len)
+                    .map(|s| match s % 97 {
+                        13 => ADC_MAX,
+                        14 => ADC_MIN,
+                        _ => ((s as f64 * 0.07 + ch as f64).sin() * 20_000.0) as i32,
+                    })
+                    .map(counts_to_float)
+                    .collect()
+            })
+            .collect();
+        for ch in 0..num_ch {
+            bufs.push((0..len).map(|s| if (s / (100 + ch)) % 3 == 1 { 0.0 } else { 1.0 }).collect());
+        }
+        bufs.push((0..len).map(|s| if s % 250 < 3 { (s / 250 % 255 + 1) as f32 } else { 0.0 }).collect());
+        Recording::from_buffs(&bufs, RecordingInfo::new(Local::now()))
+    }
+
+    #[test]
+    fn bdf_round_trip() {
+        let rec = recording(3 * 250, 3);
+        let path = std::env::temp_dir().join(format!("nigui-bdf-test-{}.bdf", std::process::id()));
+        write_bdf(&path, &rec).unwrap();
+        let read = read_edf(&path, RecordingInfo::new(Local::now()));
+        fs::remove_file(&path).unwrap();
+        let read = read.unwrap();
+
+        let counts = |r: &Recording| -> Vec<Vec<i32>> {
+            r.channels.iter().map(|c| c.iter().map(|v| float_to_counts(*v)).collect()).collect()
+        };
+        assert_eq!(read.len(), rec.len());
+        assert_eq!(counts(&read), counts(&rec));
+        assert_eq!(read.marks, rec.marks);
+        assert_eq!(read.status, rec.status);
+    }
+
+    #[test]
+    fn truncated_file() {
+        let rec = recording(250, 3);
+        let path = std::env::temp_dir().join(format!("nigui-edf-truncated-{}.edf", std::process::id()));
+        write_edf(&path, &rec).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        // cut in the signal headers, and before the end of the main header
+        let mut results = vec![];
+        for len in [600, 100] {
+            fs::write(&path, &bytes[..len]).unwrap();
+            results.push(read_edf(&path, RecordingInfo::new(Local::now())));
+        }
+        fs::remove_file(&path).unwrap();
+        assert!(results.iter().all(Result::is_err));
+    }
+}
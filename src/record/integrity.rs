@@ -0,0 +1,138 @@
+//! Integrity checks of recordings. Recordings are checked to have columns of
+//! equal length before being written, and once saved a summary goes into
+//! their sidecar: the number of samples against the number expected from the
+//! time the recording lasted, the gaps in which samples were lost and a
+//! SHA-256 checksum of the written files. `record::load` verifies the
+//! checksum, so a truncated or modified file fails to load.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use json::{object, JsonValue};
+use sha2::{Digest, Sha256};
+
+use super::{meta, Gap, Recording};
+
+/// Relative difference between the recorded and the expected number of
+/// samples above which a recording is reported as incomplete.
+pub const MAX_MISSING: f64 = 0.01;
+
+/// Integrity summary of a saved recording.
+pub struct Summary {
+    pub num_samples: usize,
+    /// Samples expected from the host time the recording lasted, not
+    /// counting pauses. `None` if the sample times are unknown.
+    pub expected_samples: Option<usize>,
+    pub gaps: Vec<Gap>,
+    /// SHA-256 of the files of the recording, in hex
+    pub checksum: String,
+}
+
+/// Fails if the columns of a recording don't have the same length.
+pub fn check_columns(rec: &Recording) -> io::Result<()> {
+    let len = rec.len();
+    let times_ok = rec.times.is_empty() || rec.times.len() == len;
+    let channels_ok = rec.channels.iter().all(|c| c.len() == len);
+    let status_ok = rec.status.len() == rec.channels.len() && rec.status.iter().all(|s| s.len() == len);
+    if times_ok && channels_ok && status_ok {
+        return Ok(());
+    }
+    let lengths = |cols: Vec<usize>| cols.iter().map(|l| l.to_string()).collect::<Vec<_>>().join(", ");
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "Recording columns have different lengths: channels [{}], status [{}], marks {}, times {}",
+            lengths(rec.channels.iter().map(Vec::len).collect()),
+            lengths(rec.status.iter().map(Vec::len).collect()),
+            len,
+            rec.times.len()
+        ),
+    ))
+}
+
+/// Checks the recording saved in `path` and writes its integrity summary to
+/// the sidecar.
+pub fn save_summary(path: &Path, rec: &Recording) -> io::Result<Summary> {
+    let period = 1.0 / rec.info.sampling_rate as f64;
+    let summary = Summary {
+        num_samples: rec.len(),
+        expected_samples: rec.elapsed().map(|t| (t / period).round() as usize),
+        gaps: rec.gaps(),
+        checksum: checksum(path)?,
+    };
+    meta::write_integrity(path, &summary.to_json(rec.info.sampling_rate))?;
+    Ok(summary)
+}
+
+/// Verifies the checksum of the recording in `path` against the one in its
+/// sidecar. Recordings without checksum pass.
+pub fn verify(path: &Path) -> io::Result<()> {
+    let Some(integrity) = meta::read_integrity(path) else {
+        return Ok(());
+    };
+    let Some(expected) = integrity["sha256"].as_str() else {
+        return Ok(());
+    };
+    if checksum(path)? == expected {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} is corrupt: its checksum does not match the one in its sidecar",
+                path.display()
+            ),
+        ))
+    }
+}
+
+/// SHA-256 of the files of the recording in `path`.
+pub fn checksum(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    for file in files(path) {
+        hasher.update(fs::read(file)?);
+    }
+    Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Files a recording is made of: BrainVision recordings have the header, the
+/// markers and the data in separate files.
+fn files(path: &Path) -> Vec<PathBuf> {
+    if path.extension().is_some_and(|e| e == "vhdr") {
+        vec![path.to_path_buf(), path.with_extension("vmrk"), path.with_extension("eeg")]
+    } else {
+        vec![path.to_path_buf()]
+    }
+}
+
+impl Summary {
+    /// Whether samples were lost: there are gaps or fewer samples than expected.
+    pub fn is_incomplete(&self) -> bool {
+        let missing = self.expected_samples.map_or(0.0, |expected| {
+            (expected as f64 - self.num_samples as f64) / expected.max(1) as f64
+        });
+        !self.gaps.is_empty() || missing > MAX_MISSING
+    }
+
+    pub fn to_json(&self, sampling_rate: u32) -> JsonValue {
+        let gaps: Vec<JsonValue> = self
+            .gaps
+            .iter()
+            .map(|g| {
+                object! {
+                    sample: g.sample,
+                    onset: g.onset,
+                    duration: g.duration,
+                    lost_samples: (g.duration * sampling_rate as f64).round() as usize,
+                }
+            })
+            .collect();
+        object! {
+            samples: self.num_samples,
+            expected_samples: self.expected_samples,
+            gaps: gaps,
+            sha256: self.checksum.as_str(),
+        }
+    }
+}
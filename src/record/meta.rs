@@ -0,0 +1,218 @@
+//! JSON sidecar with the acquisition context of a recording. It is written
+//! next to every recording (`recording.csv` -> `recording.csv.json`), so that
+//! plugins and later analyses don't have to guess the sampling rate, units
+//! or channel labels. It also holds the annotations of the recording and its
+//! integrity summary (see `integrity`).
+//!
+//! The marks and annotations are also written as a BIDS-like `events.tsv`
+//! (`recording.csv` -> `recording_events.tsv`) for tools that read those.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::prelude::*;
+use json::{object, JsonValue};
+
+use super::{Annotation, Recording, RecordingInfo};
+use crate::wave::FULL_SCALE_UV;
+use crate::wifi::{ADC_MAX, ADC_MIN};
+
+/// Path of the sidecar of the recording in `path`. It is named after the
+/// whole file name, so recordings that only differ in their format don't
+/// share it.
+pub fn sidecar_path(path: &Path) -> PathBuf {
+    let mut sidecar = path.as_os_str().to_owned();
+    sidecar.push(".json");
+    PathBuf::from(sidecar)
+}
+
+/// Writes the sidecar of the recording in `path`. The integrity summary of
+/// an existing sidecar is kept.
+pub fn write_sidecar(path: &Path, info: &RecordingInfo, annotations: &[Annotation]) -> io::Result<()> {
+    let mut value = info.to_json();
+    value["annotations"] = annotations.iter().map(Annotation::to_json).collect::<Vec<_>>().into();
+    if let Some(integrity) = read_integrity(path) {
+        value["integrity"] = integrity;
+    }
+    fs::write(sidecar_path(path), value.pretty(4))
+}
+
+/// Sets the integrity summary (see `integrity::Summary`) in the sidecar of
+/// the recording in `path`.
+pub fn write_integrity(path: &Path, integrity: &JsonValue) -> io::Result<()> {
+    let text = fs::read_to_string(sidecar_path(path))?;
+    let mut value = json::parse(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    value["integrity"] = integrity.clone();
+    fs::write(sidecar_path(path), value.pretty(4))
+}
+
+/// Reads the integrity summary from the sidecar of the recording in `path`.
+pub fn read_integrity(path: &Path) -> Option<JsonValue> {
+    let value = json::parse(&fs::read_to_string(sidecar_path(path)).ok()?).ok()?;
+    let integrity = &value["integrity"];
+    integrity.is_object().then(|| integrity.clone())
+}
+
+/// Path of the events file of the recording in `path`.
+pub fn events_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}_events.tsv", stem))
+}
+
+/// Writes the marks and annotations of the recording as a BIDS `events.tsv`
+/// file, sorted by onset as BIDS requires. Annotations have no value.
+pub fn write_events(path: &Path, rec: &Recording) -> io::Result<()> {
+    let fs = rec.info.sampling_rate as f64;
+    // (onset, row) of the marks and the annotations, written by onset
+    let mut rows: Vec<(f64, String)> = rec
+        .events()
+        .into_iter()
+        .map(|(sample, code)| {
+            let onset = sample as f64 / fs;
+            let label = rec.info.mark_label(code);
+            (onset, format!("{}\t0\t{}\t{}\t{}\tn/a", onset, label, code, sample))
+        })
+        .collect();
+    for annot in rec.annotations.iter() {
+        let sample = (annot.onset * fs).round() as usize;
+        let description = if annot.description.is_empty() { "n/a" } else { &annot.description };
+        let row = format!(
+            "{}\t{}\t{}\tn/a\t{}\t{}",
+            annot.onset, annot.duration, annot.label, sample, description
+        );
+        rows.push((annot.onset, row));
+    }
+    rows.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let mut out = io::BufWriter::new(fs::File::create(path)?);
+    writeln!(out, "onset\tduration\ttrial_type\tvalue\tsample\tdescription")?;
+    for (_, row) in rows {
+        writeln!(out, "{}", row)?;
+    }
+    out.flush()
+}
+
+/// Reads the sidecar of the recording in `path`, if there is one.
+pub fn read_sidecar(path: &Path) -> Option<RecordingInfo> {
+    let text = fs::read_to_string(sidecar_path(path)).ok()?;
+    RecordingInfo::from_json(&json::parse(&text).ok()?)
+}
+
+/// Reads the annotations from the sidecar of the recording in `path`.
+pub fn read_annotations(path: &Path) -> Vec<Annotation> {
+    let Some(value) = fs::read_to_string(sidecar_path(path))
+        .ok()
+        .and_then(|text| json::parse(&text).ok())
+    else {
+        return vec![];
+    };
+    value["annotations"].members().filter_map(Annotation::from_json).collect()
+}
+
+impl Annotation {
+    pub fn to_json(&self) -> JsonValue {
+        object! {
+            onset: self.onset,
+            duration: self.duration,
+            label: self.label.as_str(),
+            description: self.description.as_str(),
+        }
+    }
+
+    pub fn from_json(value: &JsonValue) -> Option<Self> {
+        Some(Annotation {
+            onset: value["onset"].as_f64()?,
+            duration: value["duration"].as_f64().unwrap_or(0.0),
+            label: value["label"].as_str()?.into(),
+            description: value["description"].as_str().unwrap_or_default().into(),
+        })
+    }
+}
+
+impl RecordingInfo {
+    pub fn to_json(&self) -> JsonValue {
+        let channels: Vec<JsonValue> = self
+            .labels
+            .iter()
+            .enumerate()
+            .map(|(i, label)| {
+                object! {
+                    index: i,
+                    label: label.as_str(),
+                    column: format!("channel-{}", i),
+                    status_column: format!("status ch-{}", i),
+                    physical_unit: "uV",
+                }
+            })
+            .collect();
+
+        let mut marker_labels = JsonValue::new_object();
+        for (code, label) in self.marker_labels.iter() {
+            marker_labels[code.to_string()] = label.as_str().into();
+        }
+
+        object! {
+            software: format!("NiGUI {}", env!("CARGO_PKG_VERSION")),
+            start_time: self.start.to_rfc3339(),
+            subject: self.subject.as_str(),
+            session: self.session.as_str(),
+            task: self.task.as_str(),
+            device: object! {
+                address: self.device_addr.as_str(),
+                mode: self.mode.as_str(),
+            },
+            sampling_rate: self.sampling_rate,
+            channels: channels,
+            conversion: object! {
+                adc_bits: 24,
+                adc_min: ADC_MIN,
+                adc_max: ADC_MAX,
+                full_scale_uv: FULL_SCALE_UV,
+                normalized: "2 * (counts - adc_min) / (adc_max - adc_min) - 1",
+                uv: "normalized * full_scale_uv",
+            },
+            filters: object! {
+                prefilter: self.prefilter.as_str(),
+            },
+            marker_labels: marker_labels,
+            notes: self.notes.as_str(),
+            part: self.part,
+            first_sample: self.first_sample,
+        }
+    }
+
+    /// Reads the info from a sidecar. Missing fields take the default values.
+    pub fn from_json(value: &JsonValue) -> Option<Self> {
+        let start = DateTime::parse_from_rfc3339(value["start_time"].as_str()?).ok()?;
+        let mut info = RecordingInfo::new(start.with_timezone(&Local));
+
+        if let Some(fs) = value["sampling_rate"].as_u32() {
+            info.sampling_rate = fs;
+        }
+        if value["channels"].is_array() {
+            info.labels = value["channels"]
+                .members()
+                .map(|ch| ch["label"].to_string())
+                .collect();
+        }
+        if let Some(prefilter) = value["filters"]["prefilter"].as_str() {
+            info.prefilter = prefilter.into();
+        }
+        for (code, label) in value["marker_labels"].entries() {
+            if let Ok(code) = code.parse() {
+                info.marker_labels.insert(code, label.to_string());
+            }
+        }
+        info.subject = value["subject"].as_str().unwrap_or_default().into();
+        info.session = value["session"].as_str().unwrap_or_default().into();
+        info.task = value["task"].as_str().unwrap_or_default().into();
+        info.device_addr = value["device"]["address"].as_str().unwrap_or_default().into();
+        info.mode = value["device"]["mode"].as_str().unwrap_or("normal").into();
+        info.notes = value["notes"].as_str().unwrap_or_default().into();
+        info.part = value["part"].as_u32();
+        info.first_sample = value["first_sample"].as_usize().unwrap_or(0);
+
+        Some(info)
+    }
+}
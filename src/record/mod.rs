@@ -0,0 +1,338 @@
+//! Recordings and the file formats NiGUI can write them to.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+
+use chrono::prelude::*;
+
+use crate::wave::{FULL_SCALE_UV, SAMPLING_RATE, WAVE_BUFFS_NUM};
+
+pub mod bids;
+pub mod brainvision;
+pub mod convert;
+pub mod csv;
+pub mod edf;
+pub mod integrity;
+pub mod meta;
+pub mod naming;
+pub mod ngr;
+pub mod npz;
+pub mod stream;
+
+/// Writes the recording in the format given by the extension of `path`.
+/// Fails if its columns don't have the same length.
+pub fn export(path: &Path, rec: &Recording) -> io::Result<()> {
+    integrity::check_columns(rec)?;
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("csv") => csv::write_csv(path, rec, &csv::CsvOptions::default()),
+        Some("edf") => edf::write_edf(path, rec),
+        Some("bdf") => edf::write_bdf(path, rec),
+        Some("vhdr") => brainvision::write_brainvision(path, rec),
+        Some("npz") => npz::write_npz(path, rec),
+        Some("ngr") => ngr::write_ngr(path, rec),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Unsupported file format: {}", path.display()),
+        )),
+    }
+}
+
+/// Reads a recording in any of the formats NiGUI writes. The acquisition
+/// information and the annotations are taken from its sidecar if there is
+/// one, the file itself tells the sampling rate and the channel labels.
+/// Fails if the file doesn't match the checksum in the sidecar.
+pub fn load(path: &Path) -> io::Result<Recording> {
+    integrity::verify(path)?;
+    let sidecar = meta::read_sidecar(path);
+    let info = sidecar.clone().unwrap_or_else(|| {
+        let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        RecordingInfo::new(modified.map_or_else(Local::now, DateTime::from))
+    });
+    let mut rec = match path.extension().and_then(|e| e.to_str()) {
+        Some("csv") => csv::read_csv(path, info)?,
+        Some("edf") | Some("bdf") => edf::read_edf(path, info)?,
+        Some("vhdr") => brainvision::read_brainvision(path, info)?,
+        Some("npz") => npz::read_npz(path, info)?,
+        Some("ngr") => ngr::read_ngr(path)?,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unsupported file format: {}", path.display()),
+            ))
+        }
+    };
+    if rec.info.labels.len() != rec.channels.len() && sidecar.is_none() {
+        // default labels of a file without sidecar or labels of its own
+        rec.info.labels = (0..rec.channels.len()).map(|i| format!("CH-{}", i + 1)).collect();
+    }
+    if rec.info.labels.len() != rec.channels.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} has {} channels but its information has {} labels, the sidecar may belong to another file",
+                path.display(),
+                rec.channels.len(),
+                rec.info.labels.len()
+            ),
+        ));
+    }
+    if let Some(sidecar) = sidecar {
+        // the sidecar start time is more precise than the one in file headers
+        rec.info.start = sidecar.start;
+        rec.annotations = meta::read_annotations(path);
+    }
+    Ok(rec)
+}
+
+/// A finished recording: the samples of every channel together with the
+/// information needed to describe them in a file header.
+pub struct Recording {
+    pub info: RecordingInfo,
+    /// Samples of each channel, normalized to the -1..1 range of the ADC.
+    pub channels: Vec<Vec<f32>>,
+    /// Lead-off status of each channel (`true` if the electrode is connected).
+    pub status: Vec<Vec<bool>>,
+    /// Mark code received with each sample, 0 if there was no mark.
+    pub marks: Vec<u8>,
+    /// Host wall-clock time (µs since the Unix epoch) at which each sample
+    /// was received. Empty if unknown.
+    pub times: Vec<i64>,
+    pub annotations: Vec<Annotation>,
+}
+
+/// Label of the annotations that separate the segments of a recording that
+/// was paused. The length of the pause is in their description.
+pub const BOUNDARY: &str = "boundary";
+
+/// Annotations whose label starts with this (in any case) mark bad segments,
+/// that analyses should skip. As in MNE, e.g. `BAD_blink`.
+pub const BAD_PREFIX: &str = "BAD";
+
+/// Host time between two consecutive samples (in seconds) above which the
+/// samples in between are considered lost.
+pub const GAP_THRESHOLD: f64 = 0.1;
+
+/// A span of a recording in which no samples were received.
+pub struct Gap {
+    /// Index of the first sample after the gap
+    pub sample: usize,
+    /// Start, in seconds from the beginning of the recording
+    pub onset: f64,
+    /// Host time without samples, in seconds
+    pub duration: f64,
+}
+
+/// A labeled time span of a recording.
+#[derive(Clone)]
+pub struct Annotation {
+    /// Start, in seconds from the beginning of the recording
+    pub onset: f64,
+    /// Duration in seconds, 0 for instant events
+    pub duration: f64,
+    pub label: String,
+    pub description: String,
+}
+
+/// Acquisition context of a recording.
+#[derive(Clone)]
+pub struct RecordingInfo {
+    pub start: DateTime<Local>,
+    pub sampling_rate: u32,
+    /// Channel labels, in the order of `Recording::channels`.
+    pub labels: Vec<String>,
+    /// Subject (patient) identifier, empty if unknown.
+    pub subject: String,
+    /// Session and task labels, empty if unknown.
+    pub session: String,
+    pub task: String,
+    /// Description of the filters applied to the recorded samples, empty if
+    /// none (e.g. `HP:0.5Hz N:50Hz` after `nigui convert`).
+    pub prefilter: String,
+    /// Address of the NAPSE device.
+    pub device_addr: String,
+    /// Acquisition mode of the firmware: "normal", "test", "noise" or "impedance".
+    pub mode: String,
+    /// Labels of the mark codes.
+    pub marker_labels: BTreeMap<u8, String>,
+    /// Free text notes of the operator.
+    pub notes: String,
+    /// Number (from 1) of the part of a recording that was split, see
+    /// `stream::Split`. `None` if the recording is not split.
+    pub part: Option<u32>,
+    /// Index of the first sample in the whole recording, non-zero for the
+    /// second and later parts of a split recording.
+    pub first_sample: usize,
+}
+
+impl Annotation {
+    pub fn is_bad(&self) -> bool {
+        self.label.to_uppercase().starts_with(BAD_PREFIX)
+    }
+
+    /// Label and description as a single text, for formats with only one
+    /// text field per annotation: `label: description`, or the label alone.
+    pub fn text(&self) -> String {
+        if self.description.is_empty() {
+            self.label.clone()
+        } else {
+            format!("{}: {}", self.label, self.description)
+        }
+    }
+
+    /// Splits a text written by `text` into the label and the description.
+    pub fn split_text(text: &str) -> (&str, &str) {
+        text.split_once(": ").unwrap_or((text, ""))
+    }
+}
+
+impl RecordingInfo {
+    pub fn new(start: DateTime<Local>) -> Self {
+        Self {
+            start,
+            sampling_rate: SAMPLING_RATE,
+            labels: (0..WAVE_BUFFS_NUM).map(|i| format!("CH-{}", i + 1)).collect(),
+            subject: String::new(),
+            session: String::new(),
+            task: String::new(),
+            // the samples are recorded unfiltered
+            prefilter: String::new(),
+            device_addr: String::new(),
+            mode: "normal".into(),
+            marker_labels: BTreeMap::new(),
+            notes: String::new(),
+            part: None,
+            first_sample: 0,
+        }
+    }
+
+    /// Returns the code of a mark label, the inverse of `mark_label`.
+    pub fn mark_code(&self, label: &str) -> Option<u8> {
+        let code = self.marker_labels.iter().find(|(_, l)| l.as_str() == label);
+        match code {
+            Some((code, _)) => Some(*code),
+            None => label.strip_prefix("Mark ")?.parse().ok().filter(|c| *c != 0),
+        }
+    }
+
+    /// Returns the label of a mark code, `Mark <code>` if it has none.
+    pub fn mark_label(&self, code: u8) -> String {
+        match self.marker_labels.get(&code) {
+            Some(label) if !label.is_empty() => label.clone(),
+            _ => format!("Mark {}", code),
+        }
+    }
+}
+
+impl Recording {
+    /// Builds a recording from the columns of `RECORDING_BUFFS`: one column per
+    /// channel, one lead-off status column per channel and the mark column.
+    /// Columns are truncated to the shortest one.
+    pub fn from_buffs(bufs: &[Vec<f32>], info: RecordingInfo) -> Self {
+        let len = bufs.iter().map(|b| b.len()).min().unwrap_or(0);
+        let num_ch = bufs.len().saturating_sub(1) / 2;
+
+        let channels = bufs[..num_ch].iter().map(|b| b[..len].to_vec()).collect();
+        let status = bufs[num_ch..2 * num_ch]
+            .iter()
+            .map(|b| b[..len].iter().map(|v| *v != 0.0).collect())
+            .collect();
+        let marks = bufs
+            .last()
+            .map(|b| b[..len].iter().map(|v| *v as u8).collect())
+            .unwrap_or_default();
+
+        Recording { info, channels, status, marks, times: vec![], annotations: vec![] }
+    }
+
+    /// Number of samples per channel.
+    pub fn len(&self) -> usize {
+        self.marks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Duration of the recording in seconds.
+    pub fn duration(&self) -> f64 {
+        self.len() as f64 / self.info.sampling_rate as f64
+    }
+
+    /// Host time (µs since the Unix epoch) of a sample. If the times are
+    /// unknown, it is computed from the start time and the sampling rate.
+    pub fn timestamp_us(&self, sample: usize) -> i64 {
+        match self.times.get(sample) {
+            Some(t) => *t,
+            None => {
+                let offset = sample as f64 * 1e6 / self.info.sampling_rate as f64;
+                self.info.start.timestamp_micros() + offset as i64
+            }
+        }
+    }
+
+    /// Fraction (0..1) of the samples of a channel with the electrode disconnected.
+    pub fn lead_off_ratio(&self, ch: usize) -> f64 {
+        let off = self.status[ch].iter().filter(|s| !**s).count();
+        if self.is_empty() { 0.0 } else { off as f64 / self.len() as f64 }
+    }
+
+    /// Samples of a channel in µV.
+    pub fn channel_uv(&self, ch: usize) -> impl Iterator<Item = f64> + '_ {
+        self.channels[ch].iter().map(|v| *v as f64 * FULL_SCALE_UV)
+    }
+
+    /// Finds the gaps in the host times of the samples. Pauses, that have a
+    /// boundary annotation, are not gaps. Empty if the times are unknown.
+    pub fn gaps(&self) -> Vec<Gap> {
+        let period = 1.0 / self.info.sampling_rate as f64;
+        self.times
+            .windows(2)
+            .enumerate()
+            .filter_map(|(i, t)| {
+                let dt = (t[1] - t[0]) as f64 / 1e6;
+                (dt > GAP_THRESHOLD && !self.is_pause(i + 1)).then_some(Gap {
+                    sample: i + 1,
+                    onset: (i + 1) as f64 * period,
+                    duration: dt - period,
+                })
+            })
+            .collect()
+    }
+
+    /// Host time (seconds) from the first to the last sample plus one sample
+    /// period, not counting pauses. `None` if the times are unknown.
+    pub fn elapsed(&self) -> Option<f64> {
+        let period = 1.0 / self.info.sampling_rate as f64;
+        if self.times.is_empty() {
+            return None;
+        }
+        let elapsed = self.times.windows(2).enumerate().map(|(i, t)| {
+            if self.is_pause(i + 1) { period } else { (t[1] - t[0]) as f64 / 1e6 }
+        });
+        Some(elapsed.sum::<f64>() + period)
+    }
+
+    /// Whether the recording was paused right before `sample`.
+    fn is_pause(&self, sample: usize) -> bool {
+        let period = 1.0 / self.info.sampling_rate as f64;
+        let onset = sample as f64 * period;
+        self.annotations
+            .iter()
+            .any(|a| a.label == BOUNDARY && (a.onset - onset).abs() < period)
+    }
+
+    /// Returns the `(sample, code)` pairs of the marks. A mark can span several
+    /// consecutive samples, only the first sample of each run is reported.
+    pub fn events(&self) -> Vec<(usize, u8)> {
+        let mut prev = 0;
+        let mut events = vec![];
+        for (i, &m) in self.marks.iter().enumerate() {
+            if m != 0 && m != prev {
+                events.push((i, m));
+            }
+            prev = m;
+        }
+        events
+    }
+}
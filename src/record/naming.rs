@@ -0,0 +1,95 @@
+//! Automatic file names for recordings, built from a template such as
+//! `{subject}_{task}_{date}_{counter}`. The available fields are:
+//!
+//! - `{subject}`, `{session}`, `{task}`: from the recording info
+//! - `{date}`: start of the recording, as `20240131T154502`
+//! - `{counter}`: smallest number (`001`, `002`...) that gives a new file
+//!
+//! Empty fields are left out together with their separator.
+
+use std::path::{Path, PathBuf};
+
+use super::stream::{part_path, split_path};
+use super::RecordingInfo;
+
+pub const DEFAULT_TEMPLATE: &str = "{subject}_{session}_{task}_{date}_{counter}";
+
+/// Formats the recording can be written to, as (name, extension).
+pub const FORMATS: [(&str, &str); 6] = [
+    ("CSV", "csv"),
+    ("EDF+", "edf"),
+    ("BDF", "bdf"),
+    ("BrainVision", "vhdr"),
+    ("NumPy", "npz"),
+    ("NiGUI compressed", "ngr"),
+];
+
+const SEPARATORS: [char; 3] = ['_', '-', '.'];
+
+/// Returns the path of a new recording in `dir`, named after `template` with
+/// the extension `ext`. If the template has no `{counter}`, a counter is
+/// appended when needed so that existing recordings (or their first part, if
+/// they were split) are never overwritten.
+pub fn next_path(dir: &Path, template: &str, info: &RecordingInfo, ext: &str) -> PathBuf {
+    let has_counter = template.contains("{counter}");
+    let template = if has_counter {
+        template.to_string()
+    } else {
+        format!("{}_{{counter}}", template)
+    };
+
+    (1..)
+        .map(|n| {
+            // without a counter in the template the first file is unnumbered
+            let counter = if !has_counter && n == 1 {
+                String::new()
+            } else {
+                format!("{:03}", n)
+            };
+            dir.join(format!("{}.{}", file_stem(&template, info, &counter), ext))
+        })
+        .find(|path| {
+            let first_part = split_path(path, 1);
+            [path, &first_part].iter().all(|p| !p.exists() && !part_path(p).exists())
+        })
+        .unwrap()
+}
+
+/// Fills the fields of `template`.
+pub fn file_stem(template: &str, info: &RecordingInfo, counter: &str) -> String {
+    let date = info.start.format("%Y%m%dT%H%M%S").to_string();
+    let name = template
+        .replace("{subject}", &sanitize(&info.subject))
+        .replace("{session}", &sanitize(&info.session))
+        .replace("{task}", &sanitize(&info.task))
+        .replace("{date}", &date)
+        .replace("{counter}", counter);
+
+    // drop the separators left by empty fields
+    let mut stem = String::with_capacity(name.len());
+    for c in name.chars() {
+        if SEPARATORS.contains(&c) && (stem.is_empty() || stem.ends_with(SEPARATORS)) {
+            continue;
+        }
+        stem.push(c);
+    }
+    let stem = stem.trim_end_matches(SEPARATORS);
+    if stem.is_empty() {
+        format!("recording_{}", date)
+    } else {
+        stem.to_string()
+    }
+}
+
+/// Replaces the characters that are not valid in file names on some
+/// filesystems.
+fn sanitize(s: &str) -> String {
+    s.trim()
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => '-',
+            c if c.is_control() || c.is_whitespace() => '-',
+            c => c,
+        })
+        .collect()
+}
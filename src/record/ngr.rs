@@ -0,0 +1,514 @@
+//! Compressed NiGUI recordings (`.ngr`). Samples are stored as the 24-bit
+//! counts of the ADC, so recordings of the NAPSE are stored without loss, and
+//! compressed like FLAC: every channel of every chunk is predicted with the
+//! fixed polynomial predictor (order 0 to 3) that gives the smallest
+//! residuals, and the residuals are Rice coded. The chunk index at the end
+//! tells where each chunk starts, for tools that read parts of a recording.
+//!
+//! Layout, little endian:
+//!
+//! - header: magic `NGR1`, number of channels (u16), sampling rate (u32),
+//!   samples per chunk (u32), number of samples (u64), length (u32) of the
+//!   metadata and the metadata: the sidecar JSON with the recording
+//!   information and annotations
+//! - chunks: first sample (u64), number of samples (u32), length in bytes
+//!   (u32) and a bit stream with the samples of each channel, the lead-off
+//!   status and the marks as runs, and the host times if known
+//! - index: number of chunks (u32), then first sample (u64) and file offset
+//!   (u64) of each chunk
+//! - footer: offset of the index (u64) and magic `NGRI`
+
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::Path;
+
+use super::{Annotation, Recording, RecordingInfo};
+use crate::wifi::{counts_to_float, float_to_counts};
+
+const MAGIC: &[u8; 4] = b"NGR1";
+const INDEX_MAGIC: &[u8; 4] = b"NGRI";
+const HEADER_LEN: usize = 4 + 2 + 4 + 4 + 8 + 4;
+const FOOTER_LEN: usize = 8 + 4;
+
+/// Samples per chunk, about 4 s at 250 Hz.
+pub const CHUNK_LEN: usize = 1024;
+/// Longest chunk accepted when reading, so that corrupt headers can't make
+/// the reader allocate unbounded buffers.
+const MAX_CHUNK_LEN: usize = 1 << 20;
+
+/// Rice quotients from this on are written as raw values.
+const ESCAPE: u64 = 24;
+/// Width of the raw values of samples and host times.
+const SAMPLE_BITS: u32 = 32;
+const TIME_BITS: u32 = 64;
+
+/// Writes the recording as a compressed NiGUI recording.
+pub fn write_ngr(path: &Path, rec: &Recording) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    let num_ch = rec.channels.len();
+
+    let mut metadata = rec.info.to_json();
+    metadata["annotations"] = rec.annotations.iter().map(Annotation::to_json).collect::<Vec<_>>().into();
+    let metadata = metadata.dump().into_bytes();
+
+    out.write_all(MAGIC)?;
+    out.write_all(&(num_ch as u16).to_le_bytes())?;
+    out.write_all(&rec.info.sampling_rate.to_le_bytes())?;
+    out.write_all(&(CHUNK_LEN as u32).to_le_bytes())?;
+    out.write_all(&(rec.len() as u64).to_le_bytes())?;
+    out.write_all(&(metadata.len() as u32).to_le_bytes())?;
+    out.write_all(&metadata)?;
+
+    let mut offset = (HEADER_LEN + metadata.len()) as u64;
+    let mut index = vec![];
+    for first in (0..rec.len()).step_by(CHUNK_LEN) {
+        let range = first..(first + CHUNK_LEN).min(rec.len());
+        let payload = encode_chunk(rec, range.clone());
+        out.write_all(&(first as u64).to_le_bytes())?;
+        out.write_all(&(range.len() as u32).to_le_bytes())?;
+        out.write_all(&(payload.len() as u32).to_le_bytes())?;
+        out.write_all(&payload)?;
+        index.push((first as u64, offset));
+        offset += 16 + payload.len() as u64;
+    }
+
+    out.write_all(&(index.len() as u32).to_le_bytes())?;
+    for (first, chunk_offset) in index {
+        out.write_all(&first.to_le_bytes())?;
+        out.write_all(&chunk_offset.to_le_bytes())?;
+    }
+    out.write_all(&offset.to_le_bytes())?;
+    out.write_all(INDEX_MAGIC)?;
+    out.flush()
+}
+
+fn encode_chunk(rec: &Recording, range: Range<usize>) -> Vec<u8> {
+    let mut w = BitWriter::default();
+    for channel in rec.channels.iter() {
+        let counts: Vec<i64> = channel[range.clone()].iter().map(|v| float_to_counts(*v) as i64).collect();
+        encode_column(&mut w, &counts, SAMPLE_BITS);
+    }
+    for status in rec.status.iter() {
+        let runs = runs(&status[range.clone()]);
+        w.write(runs.first().map_or(1, |(v, _)| *v as u64), 1);
+        w.write(runs.len() as u64, 32);
+        for (_, len) in runs {
+            w.write(len as u64, 32);
+        }
+    }
+    let marks = runs(&rec.marks[range.clone()]);
+    w.write(marks.len() as u64, 32);
+    for (code, len) in marks {
+        w.write(code as u64, 8);
+        w.write(len as u64, 32);
+    }
+    w.write(!rec.times.is_empty() as u64, 1);
+    if !rec.times.is_empty() {
+        encode_column(&mut w, &rec.times[range], TIME_BITS);
+    }
+    w.finish()
+}
+
+/// Splits values into runs of equal values, as (value, length).
+fn runs<T: Copy + PartialEq>(values: &[T]) -> Vec<(T, usize)> {
+    let mut runs: Vec<(T, usize)> = vec![];
+    for v in values {
+        match runs.last_mut() {
+            Some((last, len)) if last == v => *len += 1,
+            _ => runs.push((*v, 1)),
+        }
+    }
+    runs
+}
+
+/// Residuals of the fixed polynomial predictor of `order` (as in FLAC). The
+/// first `order` values are kept as they are.
+fn residuals(values: &[i64], order: usize) -> Vec<i64> {
+    (0..values.len())
+        .map(|i| {
+            let x = |k: usize| values[i - k];
+            match order {
+                _ if i < order => values[i],
+                0 => x(0),
+                1 => x(0).wrapping_sub(x(1)),
+                2 => x(0).wrapping_sub(x(1).wrapping_mul(2)).wrapping_add(x(2)),
+                _ => x(0)
+                    .wrapping_sub(x(1).wrapping_mul(3))
+                    .wrapping_add(x(2).wrapping_mul(3))
+                    .wrapping_sub(x(3)),
+            }
+        })
+        .collect()
+}
+
+/// Inverse of `residuals`, in place.
+fn integrate(values: &mut [i64], order: usize) {
+    for i in order..values.len() {
+        let x = |k: usize| values[i - k];
+        let prediction = match order {
+            0 => 0,
+            1 => x(1),
+            2 => x(1).wrapping_mul(2).wrapping_sub(x(2)),
+            _ => x(1).wrapping_mul(3).wrapping_sub(x(2).wrapping_mul(3)).wrapping_add(x(3)),
+        };
+        values[i] = values[i].wrapping_add(prediction);
+    }
+}
+
+fn zigzag(v: i64) -> u64 {
+    ((v << 1) ^ (v >> 63)) as u64
+}
+
+fn unzigzag(u: u64) -> i64 {
+    (u >> 1) as i64 ^ -((u & 1) as i64)
+}
+
+/// Writes a column with the predictor that gives the smallest residuals:
+/// the order (2 bits) and Rice parameter (6 bits), the first values raw and
+/// the Rice coded residuals.
+fn encode_column(w: &mut BitWriter, values: &[i64], raw_bits: u32) {
+    let (order, residuals) = (0..=3)
+        .map(|order| (order, residuals(values, order)))
+        .min_by_key(|(order, r)| r[(*order).min(r.len())..].iter().map(|v| zigzag(*v) as u128).sum::<u128>())
+        .unwrap();
+    let order = order.min(values.len());
+
+    let coded = &residuals[order..];
+    let mean = coded.iter().map(|v| zigzag(*v) as u128).sum::<u128>() / coded.len().max(1) as u128;
+    let k = (128 - mean.leading_zeros()).saturating_sub(1).min(raw_bits - 1) as u64;
+
+    w.write(order as u64, 2);
+    w.write(k, 6);
+    for v in &residuals[..order] {
+        w.write(zigzag(*v), raw_bits);
+    }
+    for v in coded {
+        let u = zigzag(*v);
+        let q = u >> k;
+        w.write_unary(q.min(ESCAPE), ESCAPE);
+        if q < ESCAPE {
+            w.write(u, k as u32);
+        } else {
+            w.write(u, raw_bits);
+        }
+    }
+}
+
+fn decode_column(r: &mut BitReader, len: usize, raw_bits: u32) -> io::Result<Vec<i64>> {
+    let order = (r.read(2)? as usize).min(len);
+    let k = r.read(6)? as u32;
+    let mut values = Vec::with_capacity(len);
+    for _ in 0..order {
+        values.push(unzigzag(r.read(raw_bits)?));
+    }
+    for _ in order..len {
+        let q = r.read_unary(ESCAPE)?;
+        let u = if q < ESCAPE { (q << k) | r.read(k)? } else { r.read(raw_bits)? };
+        values.push(unzigzag(u));
+    }
+    integrate(&mut values, order);
+    Ok(values)
+}
+
+/// Reads a compressed NiGUI recording. The lengths in the file are checked
+/// against the file size and the chunk length before anything is allocated.
+pub fn read_ngr(path: &Path) -> io::Result<Recording> {
+    let mut file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let mut header = [0; HEADER_LEN];
+    file.read_exact(&mut header)
+        .map_err(|_| invalid(path, "not a NiGUI recording"))?;
+    if &header[..4] != MAGIC {
+        return Err(invalid(path, "not a NiGUI recording"));
+    }
+    let num_channels = u16::from_le_bytes([header[4], header[5]]) as usize;
+    let sampling_rate = u32::from_le_bytes(header[6..10].try_into().unwrap());
+    let chunk_len = u32::from_le_bytes(header[10..14].try_into().unwrap()) as usize;
+    let num_samples = u64::from_le_bytes(header[14..22].try_into().unwrap());
+    let metadata_len = u32::from_le_bytes(header[22..26].try_into().unwrap()) as u64;
+    if chunk_len > MAX_CHUNK_LEN {
+        return Err(invalid(path, "invalid chunk length"));
+    }
+    if HEADER_LEN as u64 + metadata_len + FOOTER_LEN as u64 > file_len {
+        return Err(invalid(path, "the file is truncated"));
+    }
+
+    let mut metadata = vec![0; metadata_len as usize];
+    file.read_exact(&mut metadata)?;
+    let metadata = std::str::from_utf8(&metadata)
+        .ok()
+        .and_then(|text| json::parse(text).ok())
+        .ok_or_else(|| invalid(path, "invalid metadata"))?;
+    let mut info = RecordingInfo::from_json(&metadata).ok_or_else(|| invalid(path, "invalid metadata"))?;
+    info.sampling_rate = sampling_rate;
+    info.labels.resize_with(num_channels, String::new);
+    let annotations = metadata["annotations"].members().filter_map(Annotation::from_json).collect();
+
+    let mut footer = [0; FOOTER_LEN];
+    file.seek(SeekFrom::End(-(FOOTER_LEN as i64)))
+        .and_then(|_| file.read_exact(&mut footer))
+        .map_err(|_| invalid(path, "the file is truncated"))?;
+    if &footer[8..] != INDEX_MAGIC {
+        return Err(invalid(path, "the file is truncated"));
+    }
+    let index_offset = u64::from_le_bytes(footer[..8].try_into().unwrap());
+    file.seek(SeekFrom::Start(index_offset))?;
+    let mut buf = [0; 4];
+    file.read_exact(&mut buf)?;
+    let num_chunks = u32::from_le_bytes(buf) as u64;
+    if index_offset + 4 + 16 * num_chunks > file_len {
+        return Err(invalid(path, "the file is truncated"));
+    }
+    let mut offsets = vec![];
+    for _ in 0..num_chunks {
+        let mut entry = [0; 16];
+        file.read_exact(&mut entry)?;
+        offsets.push(u64::from_le_bytes(entry[8..].try_into().unwrap()));
+    }
+
+    let mut rec = Recording {
+        info,
+        channels: vec![vec![]; num_channels],
+        status: vec![vec![]; num_channels],
+        marks: vec![],
+        times: vec![],
+        annotations,
+    };
+    for offset in offsets {
+        read_chunk(&mut file, offset, file_len, chunk_len, &mut rec).map_err(|e| invalid(path, &e.to_string()))?;
+    }
+    if rec.len() as u64 != num_samples {
+        return Err(invalid(path, "missing chunks"));
+    }
+    Ok(rec)
+}
+
+/// Decodes the chunk at `offset` and appends its samples to `rec`.
+fn read_chunk(file: &mut File, offset: u64, file_len: u64, chunk_len: usize, rec: &mut Recording) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut header = [0; 16];
+    file.read_exact(&mut header)?;
+    let first = u64::from_le_bytes(header[..8].try_into().unwrap());
+    let len = u32::from_le_bytes(header[8..12].try_into().unwrap()) as usize;
+    let payload_len = u32::from_le_bytes(header[12..].try_into().unwrap()) as u64;
+    if first != rec.len() as u64 || len > chunk_len || offset + 16 + payload_len > file_len {
+        return Err(corrupt_chunk());
+    }
+    let mut payload = vec![0; payload_len as usize];
+    file.read_exact(&mut payload)?;
+
+    let mut r = BitReader { bytes: &payload, pos: 0 };
+    let mut channels = vec![];
+    for _ in 0..rec.channels.len() {
+        let counts = decode_column(&mut r, len, SAMPLE_BITS)?;
+        channels.push(counts.iter().map(|c| counts_to_float(*c as i32)).collect::<Vec<_>>());
+    }
+    let mut statuses = vec![];
+    for _ in 0..rec.channels.len() {
+        let mut value = r.read(1)? == 1;
+        let mut status = Vec::with_capacity(len);
+        for _ in 0..r.read(32)? {
+            status.extend(std::iter::repeat_n(value, run_len(&mut r, status.len(), len)?));
+            value = !value;
+        }
+        statuses.push(status);
+    }
+    let mut marks = Vec::with_capacity(len);
+    for _ in 0..r.read(32)? {
+        let code = r.read(8)? as u8;
+        marks.extend(std::iter::repeat_n(code, run_len(&mut r, marks.len(), len)?));
+    }
+    let times = if r.read(1)? == 1 { decode_column(&mut r, len, TIME_BITS)? } else { vec![] };
+
+    if statuses.iter().any(|s| s.len() != len) || marks.len() != len {
+        return Err(corrupt_chunk());
+    }
+    // host times are only kept if every chunk has them
+    if times.is_empty() || rec.times.len() != rec.len() {
+        rec.times.clear();
+    } else {
+        rec.times.extend(times);
+    }
+    for (ch, (samples, status)) in channels.into_iter().zip(statuses).enumerate() {
+        rec.channels[ch].extend(samples);
+        rec.status[ch].extend(status);
+    }
+    rec.marks.extend(marks);
+    Ok(())
+}
+
+/// Reads the length of a run that starts at `start`, checking that it ends
+/// within the chunk of `len` samples.
+fn run_len(r: &mut BitReader, start: usize, len: usize) -> io::Result<usize> {
+    let run = r.read(32)? as usize;
+    if start + run > len {
+        return Err(corrupt_chunk());
+    }
+    Ok(run)
+}
+
+fn corrupt_chunk() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "corrupt chunk")
+}
+
+fn invalid(path: &Path, msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), msg))
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u64,
+    /// Number of bits in `acc`
+    len: u32,
+}
+
+impl BitWriter {
+    /// Writes the `bits` lowest bits of `value`, most significant first.
+    fn write(&mut self, value: u64, bits: u32) {
+        if bits > 32 {
+            self.write(value >> 32, bits - 32);
+            self.write(value, 32);
+            return;
+        }
+        let mask = (1u64 << bits) - 1;
+        self.acc = (self.acc << bits) | (value & mask);
+        self.len += bits;
+        while self.len >= 8 {
+            self.len -= 8;
+            self.bytes.push((self.acc >> self.len) as u8);
+        }
+        self.acc &= (1 << self.len) - 1;
+    }
+
+    /// Writes `n` ones and a zero, without the zero if `n` is `max`.
+    fn write_unary(&mut self, n: u64, max: u64) {
+        for _ in 0..n {
+            self.write(1, 1);
+        }
+        if n < max {
+            self.write(0, 1);
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.len > 0 {
+            self.write(0, 8 - self.len);
+        }
+        self.bytes
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    /// Position in bits
+    pos: usize,
+}
+
+impl BitReader<'_> {
+    fn read(&mut self, bits: u32) -> io::Result<u64> {
+        let mut value = 0;
+        let mut left = bits as usize;
+        while left > 0 {
+            let byte = *self
+                .bytes
+                .get(self.pos / 8)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "corrupt chunk"))?;
+            let offset = self.pos % 8;
+            let take = left.min(8 - offset);
+            let part = (byte >> (8 - offset - take)) as u64 & ((1 << take) - 1);
+            value = (value << take) | part;
+            self.pos += take;
+            left -= take;
+        }
+        Ok(value)
+    }
+
+    /// Reads ones until a zero, at most `max` ones.
+    fn read_unary(&mut self, max: u64) -> io::Result<u64> {
+        let mut n = 0;
+        while n < max && self.read(1)? == 1 {
+            n += 1;
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Smooth signal with spikes to the ends of the ADC range, so that the
+    /// residuals of the spikes take the escape path.
+    fn counts(ch: usize, len: usize) -> Vec<i32> {
+        (0..len)
+            .map(|s| match s % 97 {
+                13 => 8_388_607,
+                14 => -8_388_608,
+                _ => ((s as f64 * 0.07 + ch as f64).sin() * 20_000.0) as i32,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn column_round_trip() {
+        for len in [0, 1, 2, 3, 5, 1023] {
+            let values: Vec<i64> = counts(0, len).into_iter().map(i64::from).collect();
+            let mut w = BitWriter::default();
+            encode_column(&mut w, &values, SAMPLE_BITS);
+            let bytes = w.finish();
+            let mut r = BitReader { bytes: &bytes, pos: 0 };
+            assert_eq!(decode_column(&mut r, len, SAMPLE_BITS).unwrap(), values, "len {}", len);
+        }
+    }
+
+    #[test]
+    fn recording_round_trip() {
+        // the last chunk is shorter and of odd length
+        let len = 2 * CHUNK_LEN + 37;
+        let num_ch = 3;
+        let mut bufs: Vec<Vec<f32>> = (0..num_ch)
+            .map(|ch| counts(ch, len).into_iter().map(counts_to_float).collect())
+            .collect();
+        for ch in 0..num_ch {
+            bufs.push((0..len).map(|s| if (s / (100 + ch)) % 3 == 1 { 0.0 } else { 1.0 }).collect());
+        }
+        bufs.push((0..len).map(|s| if s % 250 < 3 { (s / 250 % 255 + 1) as f32 } else { 0.0 }).collect());
+        let mut rec = Recording::from_buffs(&bufs, RecordingInfo::new(chrono::Local::now()));
+        rec.times = (0..len as i64).map(|s| 1_700_000_000_000_000 + s * 4000 + s % 7).collect();
+        rec.annotations.push(Annotation {
+            onset: 1.5,
+            duration: 0.5,
+            label: "BAD_blink".into(),
+            description: String::new(),
+        });
+
+        let path = std::env::temp_dir().join(format!("nigui-ngr-test-{}.ngr", std::process::id()));
+        write_ngr(&path, &rec).unwrap();
+        let read = read_ngr(&path);
+        std::fs::remove_file(&path).unwrap();
+        let read = read.unwrap();
+
+        assert_eq!(read.len(), len);
+        assert_eq!(read.channels, rec.channels);
+        assert_eq!(read.status, rec.status);
+        assert_eq!(read.marks, rec.marks);
+        assert_eq!(read.times, rec.times);
+        assert_eq!(read.annotations.len(), 1);
+        assert_eq!(read.annotations[0].label, "BAD_blink");
+    }
+
+    #[test]
+    fn truncated_file() {
+        let bufs = vec![vec![0.0; 10]; 3];
+        let rec = Recording::from_buffs(&bufs, RecordingInfo::new(chrono::Local::now()));
+        let path = std::env::temp_dir().join(format!("nigui-ngr-truncated-{}.ngr", std::process::id()));
+        write_ngr(&path, &rec).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &bytes[..bytes.len() / 2]).unwrap();
+        let read = read_ngr(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(read.is_err());
+    }
+}
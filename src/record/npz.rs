@@ -0,0 +1,247 @@
+//! NumPy `.npz` reader and writer, so that Python plugins can load recordings with
+//! `numpy.load` instead of parsing CSV files. The archive contains:
+//!
+//! - `data`: float32 array of shape (samples, channels), in µV
+//! - `status`: uint8 array of shape (samples, channels), 1 if the electrode is connected
+//! - `marks`: uint8 array of shape (samples,)
+//! - `sampling_rate`: float64 scalar, in Hz
+//! - `channel_names`: unicode array of shape (channels,)
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use super::{Recording, RecordingInfo};
+use crate::wave::FULL_SCALE_UV;
+
+pub fn write_npz(path: &Path, rec: &Recording) -> io::Result<()> {
+    let mut zip = ZipWriter::new(File::create(path)?);
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    let (len, num_ch) = (rec.len(), rec.channels.len());
+
+    let mut data = Vec::with_capacity(4 * len * num_ch);
+    let mut channels: Vec<_> = (0..num_ch).map(|ch| rec.channel_uv(ch)).collect();
+    for _ in 0..len {
+        for channel in channels.iter_mut() {
+            let v = channel.next().unwrap_or(0.0) as f32;
+            data.extend_from_slice(&v.to_le_bytes());
+        }
+    }
+    write_npy(&mut zip, options, "data", "<f4", &[len, num_ch], &data)?;
+
+    let mut status = Vec::with_capacity(len * num_ch);
+    for s in 0..len {
+        for ch_status in rec.status.iter() {
+            status.push(ch_status[s] as u8);
+        }
+    }
+    write_npy(&mut zip, options, "status", "|u1", &[len, num_ch], &status)?;
+
+    write_npy(&mut zip, options, "marks", "|u1", &[len], &rec.marks)?;
+
+    let fs = (rec.info.sampling_rate as f64).to_le_bytes();
+    write_npy(&mut zip, options, "sampling_rate", "<f8", &[], &fs)?;
+
+    // fixed width UTF-32 strings
+    let labels = &rec.info.labels[..num_ch.min(rec.info.labels.len())];
+    let width = labels.iter().map(|l| l.chars().count()).max().unwrap_or(0).max(1);
+    let mut names = vec![];
+    for label in labels {
+        let mut chars: Vec<u32> = label.chars().map(|c| c as u32).collect();
+        chars.resize(width, 0);
+        names.extend(chars.iter().flat_map(|c| c.to_le_bytes()));
+    }
+    let descr = format!("<U{}", width);
+    write_npy(&mut zip, options, "channel_names", &descr, &[labels.len()], &names)?;
+
+    zip.finish()?;
+    Ok(())
+}
+
+/// Reads a `.npz` archive written by `write_npz`. The sampling rate and
+/// channel labels are taken from the archive, the rest of `info` is kept.
+pub fn read_npz(path: &Path, mut info: RecordingInfo) -> io::Result<Recording> {
+    let mut zip = ZipArchive::new(File::open(path)?)?;
+    let invalid = |name: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: invalid or missing array {}", path.display(), name),
+        )
+    };
+
+    let (descr, shape, data) = read_npy(&mut zip, "data")?;
+    let (len, num_ch) = match shape[..] {
+        [len, num_ch] if descr == "<f4" && num_ch > 0 => (len, num_ch),
+        _ => return Err(invalid("data")),
+    };
+    // the shape must match the samples before anything is allocated for them
+    let size = len.checked_mul(num_ch).ok_or_else(|| invalid("data"))?;
+    if size.checked_mul(4) != Some(data.len()) {
+        return Err(invalid("data"));
+    }
+    let mut channels = vec![Vec::with_capacity(len); num_ch];
+    for (i, v) in data.chunks_exact(4).enumerate() {
+        let uv = f32::from_le_bytes([v[0], v[1], v[2], v[3]]) as f64;
+        channels[i % num_ch].push((uv / FULL_SCALE_UV) as f32);
+    }
+
+    let status = match read_npy(&mut zip, "status") {
+        Ok((_, shape, data)) if shape == [len, num_ch] && data.len() == size => (0..num_ch)
+            .map(|ch| (0..len).map(|s| data[s * num_ch + ch] != 0).collect())
+            .collect(),
+        _ => vec![vec![true; len]; num_ch],
+    };
+    let marks = match read_npy(&mut zip, "marks") {
+        Ok((_, shape, data)) if shape == [len] && data.len() == len => data,
+        _ => vec![0; len],
+    };
+
+    let (_, _, fs) = read_npy(&mut zip, "sampling_rate")?;
+    let fs: [u8; 8] = fs.try_into().map_err(|_| invalid("sampling_rate"))?;
+    info.sampling_rate = f64::from_le_bytes(fs).round() as u32;
+
+    if let Ok((descr, _, names)) = read_npy(&mut zip, "channel_names") {
+        let width: usize = descr.trim_start_matches("<U").parse().map_err(|_| invalid("channel_names"))?;
+        let name_size = width.max(1).checked_mul(4).ok_or_else(|| invalid("channel_names"))?;
+        info.labels = names
+            .chunks_exact(name_size)
+            .map(|name| {
+                name.chunks_exact(4)
+                    .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+                    .take_while(|c| *c != 0)
+                    .filter_map(char::from_u32)
+                    .collect()
+            })
+            .collect();
+    }
+
+    Ok(Recording { info, channels, status, marks, times: vec![], annotations: vec![] })
+}
+
+/// Recording in the `.npz` format, see `ensure_npz`.
+pub struct NpzFile {
+    pub path: PathBuf,
+    /// The file was converted and is removed, with its sidecar, on drop
+    converted: bool,
+}
+
+impl Drop for NpzFile {
+    fn drop(&mut self) {
+        if self.converted {
+            let _ = fs::remove_file(&self.path);
+            let _ = fs::remove_file(super::meta::sidecar_path(&self.path));
+        }
+    }
+}
+
+/// Returns the recording in `path` as `.npz`: `path` itself if it is an
+/// `.npz` file, otherwise the recording is converted into the temp directory
+/// together with its sidecar, so that its annotations can be read. Converted
+/// files get a unique name and are removed when the `NpzFile` is dropped.
+pub fn ensure_npz(path: &Path) -> io::Result<NpzFile> {
+    if path.extension().is_some_and(|e| e == "npz") {
+        return Ok(NpzFile { path: path.to_path_buf(), converted: false });
+    }
+    let rec = super::load(path)?;
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos());
+    let name = format!("{}-{}-{}.npz", stem, std::process::id(), nanos);
+    let out = NpzFile { path: std::env::temp_dir().join(name), converted: true };
+    write_npz(&out.path, &rec)?;
+    super::meta::write_sidecar(&out.path, &rec.info, &rec.annotations)?;
+    Ok(out)
+}
+
+/// Adds an array to the archive in the `.npy` format (version 1.0).
+fn write_npy(
+    zip: &mut ZipWriter<File>,
+    options: FileOptions,
+    name: &str,
+    descr: &str,
+    shape: &[usize],
+    data: &[u8],
+) -> io::Result<()> {
+    let shape = match shape {
+        [n] => format!("({},)", n),
+        _ => format!("({})", shape.iter().map(|n| n.to_string()).collect::<Vec<_>>().join(", ")),
+    };
+    let mut header = format!(
+        "{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}",
+        descr, shape
+    );
+    // magic (6) + version (2) + header length (2) + header must be aligned to 64 bytes
+    let total = 10 + header.len() + 1;
+    header.push_str(&" ".repeat((64 - total % 64) % 64));
+    header.push('\n');
+
+    zip.start_file(format!("{}.npy", name), options)?;
+    zip.write_all(b"\x93NUMPY\x01\x00")?;
+    zip.write_all(&(header.len() as u16).to_le_bytes())?;
+    zip.write_all(header.as_bytes())?;
+    zip.write_all(data)
+}
+
+/// Reads an array from the archive. Returns its dtype, shape and raw data.
+fn read_npy(zip: &mut ZipArchive<File>, name: &str) -> io::Result<(String, Vec<usize>, Vec<u8>)> {
+    let invalid = || {
+        io::Error::new(io::ErrorKind::InvalidData, format!("invalid array {}", name))
+    };
+    let mut bytes = vec![];
+    zip.by_name(&format!("{}.npy", name))?.read_to_end(&mut bytes)?;
+    if !bytes.starts_with(b"\x93NUMPY") || bytes.len() < 10 {
+        return Err(invalid());
+    }
+    // version 1.0 has a 2 bytes header length, later versions 4 bytes
+    let (header_start, header_len) = match bytes[6] {
+        1 => (10, u16::from_le_bytes([bytes[8], bytes[9]]) as usize),
+        _ if bytes.len() >= 12 => (12, u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]) as usize),
+        _ => return Err(invalid()),
+    };
+    let header = bytes
+        .get(header_start..header_start + header_len)
+        .and_then(|h| std::str::from_utf8(h).ok())
+        .ok_or_else(invalid)?;
+    if header.contains("'fortran_order': True") {
+        return Err(invalid());
+    }
+
+    let descr = header
+        .split("'descr':")
+        .nth(1)
+        .and_then(|d| d.split('\'').nth(1))
+        .ok_or_else(invalid)?
+        .to_string();
+    let shape = header
+        .split("'shape':")
+        .nth(1)
+        .and_then(|s| s.split(['(', ')']).nth(1))
+        .ok_or_else(invalid)?
+        .split(',')
+        .filter(|d| !d.trim().is_empty())
+        .map(|d| d.trim().parse().map_err(|_| invalid()))
+        .collect::<io::Result<Vec<usize>>>()?;
+
+    Ok((descr, shape, bytes.split_off(header_start + header_len)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncated_file() {
+        let bufs = vec![vec![0.0; 10]; 3];
+        let rec = Recording::from_buffs(&bufs, RecordingInfo::new(chrono::Local::now()));
+        let path = std::env::temp_dir().join(format!("nigui-npz-truncated-{}.npz", std::process::id()));
+        write_npz(&path, &rec).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &bytes[..bytes.len() / 2]).unwrap();
+        let read = read_npz(&path, RecordingInfo::new(chrono::Local::now()));
+        std::fs::remove_file(&path).unwrap();
+        assert!(read.is_err());
+    }
+}
@@ -0,0 +1,504 @@
+//! Streaming recorder. While recording, a background thread moves the data
+//! from `RECORDING_BUFFS` to a `.part` CSV file next to the output file, so
+//! that a crash only loses the last second of data. When the recording stops
+//! the part file becomes the output file (converted to its format if needed).
+//!
+//! When the recording stops, its integrity summary (see `integrity`) is
+//! written to the sidecar.
+//!
+//! Recordings can be given a duration, they then stop by themselves after
+//! that many samples.
+//!
+//! Long recordings can be split into parts of a given length or size (see
+//! `Split`). Each part is a complete recording with its own sidecar, whose
+//! start time is the time of its first sample and whose `first_sample` is
+//! its index in the whole recording, so that the parts can be concatenated
+//! exactly. Marks and annotations go to the part they fall in.
+//!
+//! The output file and the information of the ongoing recording (or part)
+//! are kept in a journal file next to it (`rest.edf` -> `rest.edf.journal`),
+//! that is used to recover the part file if NiGUI did not stop the recording
+//! cleanly.
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use chrono::prelude::*;
+use json::object;
+
+use super::csv::{self, CsvOptions};
+use super::{export, integrity, meta, Annotation, Recording, RecordingInfo, BOUNDARY};
+use crate::log_err;
+use crate::wave::{RECORDING_BUFFS, RECORDING_FLAG, RECORDING_TIMES, WAVE_BUFFS_NUM};
+
+/// Time between writes of the recording buffers to disk.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// When to split a recording into parts. Parts are named after the output
+/// file, `rest.edf` is recorded as `rest_part-001.edf`, `rest_part-002.edf`...
+#[derive(Clone, Copy, Default, PartialEq)]
+pub struct Split {
+    /// Length of the parts in minutes
+    pub minutes: Option<f64>,
+    /// Size of the parts in MB. Compressed formats (npz, ngr) are split on
+    /// their uncompressed size, so their parts are smaller.
+    pub megabytes: Option<f64>,
+}
+
+impl Split {
+    pub fn is_enabled(&self) -> bool {
+        self.minutes.is_some() || self.megabytes.is_some()
+    }
+}
+
+/// Part of the recording being written, shared by the recorder and its
+/// writer thread.
+struct Current {
+    /// Output file of the part
+    path: PathBuf,
+    info: RecordingInfo,
+    /// Annotations of the whole recording, onsets are from its start
+    annotations: Vec<Annotation>,
+}
+
+pub struct Recorder {
+    path: PathBuf,
+    info: RecordingInfo,
+    current: Arc<Mutex<Current>>,
+    /// Start of the current pause, if paused
+    paused: Option<Instant>,
+    /// Number of samples written to the part files
+    written: Arc<AtomicUsize>,
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<io::Result<()>>,
+}
+
+impl Recorder {
+    /// Starts recording to `path`. The format of the file is given by its
+    /// extension (see `record::export`), `csv_opts` are used if it is a CSV.
+    /// With a `duration`, the recording stops once it has that many seconds
+    /// of data (not counting pauses) and `is_running` returns `false`.
+    pub fn start(
+        path: PathBuf,
+        info: RecordingInfo,
+        csv_opts: CsvOptions,
+        duration: Option<Duration>,
+        split: Split,
+    ) -> io::Result<Self> {
+        // the part file is converted to other formats on stop, so it must keep all the data
+        let csv_opts = if path.extension().is_some_and(|e| e == "csv") {
+            csv_opts
+        } else {
+            CsvOptions::default()
+        };
+
+        let mut part_info = info.clone();
+        let part_path = if split.is_enabled() {
+            part_info.part = Some(1);
+            split_path(&path, 1)
+        } else {
+            path.clone()
+        };
+        let out = open_part(&part_path, &part_info, &csv_opts)?;
+
+        {
+            let mut buffs = RECORDING_BUFFS.write().unwrap();
+            buffs.clear();
+            // push a vec for each column in the CSV
+            for _ in 0..(WAVE_BUFFS_NUM * 2 + 1) {
+                buffs.push(vec![]);
+            }
+            RECORDING_TIMES.write().unwrap().clear();
+        }
+        *RECORDING_FLAG.write().unwrap() = true;
+
+        let fs = info.sampling_rate as f64;
+        let current = Arc::new(Mutex::new(Current {
+            path: part_path,
+            info: part_info,
+            annotations: vec![],
+        }));
+        let written = Arc::new(AtomicUsize::new(0));
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut writer = Writer {
+            path: path.clone(),
+            out,
+            opts: csv_opts,
+            max_samples: duration.map(|d| (d.as_secs_f64() * fs).round() as usize),
+            split_samples: split.minutes.map(|m| ((m * 60.0 * fs).round() as usize).max(1)),
+            split_bytes: split.megabytes.map(|mb| (mb * 1e6) as u64),
+            full: false,
+            current: current.clone(),
+            written: written.clone(),
+            stop: stop.clone(),
+        };
+        let thread = thread::spawn(move || {
+            let res = writer.run();
+            if let Err(e) = &res {
+                *RECORDING_FLAG.write().unwrap() = false;
+                log_err(format!("Recording stopped, failed to write to disk: {}", e));
+            }
+            res
+        });
+
+        Ok(Recorder {
+            path,
+            info,
+            current,
+            paused: None,
+            written,
+            stop,
+            thread,
+        })
+    }
+
+    /// Path of the output file. Split recordings are written to the parts
+    /// of this path, see `Split`.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns `false` if the recording stopped because of an error or
+    /// because it reached its duration. `stop` tells which one.
+    pub fn is_running(&self) -> bool {
+        !self.thread.is_finished()
+    }
+
+    /// Number of samples recorded so far.
+    pub fn num_samples(&self) -> usize {
+        // the writer thread moves samples from the buffers to the file
+        // holding this lock
+        let _buffs = RECORDING_BUFFS.read().unwrap();
+        self.written.load(Ordering::SeqCst) + RECORDING_TIMES.read().unwrap().len()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.is_some()
+    }
+
+    /// Stops adding samples to the recording until `resume` is called.
+    pub fn pause(&mut self) {
+        if self.paused.is_none() {
+            *RECORDING_FLAG.write().unwrap() = false;
+            self.paused = Some(Instant::now());
+        }
+    }
+
+    /// Resumes a paused recording, adding a boundary annotation between the
+    /// samples before and after the pause.
+    pub fn resume(&mut self) {
+        let Some(paused) = self.paused.take() else { return };
+
+        let num_samples = {
+            // hold the locks so that the writer thread can't move samples meanwhile
+            let _buffs = RECORDING_BUFFS.write().unwrap();
+            let times = RECORDING_TIMES.write().unwrap();
+            *RECORDING_FLAG.write().unwrap() = true;
+            self.written.load(Ordering::SeqCst) + times.len()
+        };
+
+        let mut current = self.current.lock().unwrap();
+        current.annotations.push(Annotation {
+            onset: num_samples as f64 / self.info.sampling_rate as f64,
+            duration: 0.0,
+            label: BOUNDARY.into(),
+            description: format!("pause of {:.3} s", paused.elapsed().as_secs_f64()),
+        });
+        let annotations = part_annotations(&current.annotations, &current.info, None);
+        if let Err(e) = write_journal(&current.path, &current.info, &annotations) {
+            log_err(format!("Failed to update the recording journal: {}", e));
+        }
+    }
+
+    /// Stops the recording and writes the output file (the last part if the
+    /// recording is split). Returns its path.
+    pub fn stop(self) -> io::Result<PathBuf> {
+        *RECORDING_FLAG.write().unwrap() = false;
+        self.stop.store(true, Ordering::SeqCst);
+        self.thread
+            .join()
+            .map_err(|_| io::Error::other("Recorder thread panicked"))??;
+        let current = self.current.lock().unwrap();
+        let annotations = part_annotations(&current.annotations, &current.info, None);
+        finalize(&current.path, current.info.clone(), annotations)?;
+        Ok(current.path.clone())
+    }
+}
+
+/// Writer thread of a recorder.
+struct Writer {
+    /// Output file of the recording
+    path: PathBuf,
+    /// Part file of the current part
+    out: BufWriter<File>,
+    opts: CsvOptions,
+    max_samples: Option<usize>,
+    split_samples: Option<usize>,
+    split_bytes: Option<u64>,
+    /// Whether the current part reached the split length or size. The next
+    /// part is started with the next sample.
+    full: bool,
+    current: Arc<Mutex<Current>>,
+    written: Arc<AtomicUsize>,
+    stop: Arc<AtomicBool>,
+}
+
+impl Writer {
+    /// Writes the contents of the recording buffers to the part file every
+    /// `FLUSH_INTERVAL`, until `stop` is set or `max_samples` are written.
+    fn run(&mut self) -> io::Result<()> {
+        loop {
+            let stopped = self.stop.load(Ordering::SeqCst);
+            if !stopped {
+                thread::sleep(FLUSH_INTERVAL);
+            }
+
+            // chunks end at the end of a part, the rest goes to the next part
+            while self.write_chunk()? {}
+
+            let done = self
+                .max_samples
+                .is_some_and(|max| self.written.load(Ordering::SeqCst) >= max);
+            if done {
+                *RECORDING_FLAG.write().unwrap() = false;
+            }
+            if stopped || done {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Moves samples from the buffers to the part file. Returns `true` if
+    /// some were left because the part is full.
+    fn write_chunk(&mut self) -> io::Result<bool> {
+        let written = self.written.load(Ordering::SeqCst);
+        let remaining = self.max_samples.map_or(usize::MAX, |max| max.saturating_sub(written));
+        let available = {
+            let _buffs = RECORDING_BUFFS.read().unwrap();
+            RECORDING_TIMES.read().unwrap().len().min(remaining)
+        };
+        if available == 0 {
+            return Ok(false);
+        }
+        if self.full {
+            self.next_part()?;
+        }
+
+        let info = self.current.lock().unwrap().info.clone();
+        let part_len = written - info.first_sample;
+        let (chunk, left) = {
+            let mut buffs = RECORDING_BUFFS.write().unwrap();
+            let mut times = RECORDING_TIMES.write().unwrap();
+            // the acquisition thread pushes whole samples, uneven columns are a bug
+            if buffs.iter().any(|b| b.len() != times.len()) {
+                let lengths: Vec<String> = buffs.iter().map(|b| b.len().to_string()).collect();
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "recording buffers out of sync (columns [{}], times {})",
+                        lengths.join(", "),
+                        times.len()
+                    ),
+                ));
+            }
+            let mut len = times.len().min(remaining);
+            if let Some(split) = self.split_samples {
+                len = len.min(split - part_len);
+            }
+            let cols: Vec<Vec<f32>> = buffs.iter_mut().map(|b| b.drain(..len).collect()).collect();
+            let mut chunk = Recording::from_buffs(&cols, info);
+            chunk.times = times.drain(..len).collect();
+            (chunk, times.len().min(remaining - len))
+        };
+        csv::write_rows(&mut self.out, &chunk, written, &self.opts)?;
+        self.out.flush()?;
+        self.out.get_ref().sync_data()?;
+        self.written.fetch_add(chunk.len(), Ordering::SeqCst);
+
+        let part_len = part_len + chunk.len();
+        self.full = self.split_samples.is_some_and(|split| part_len >= split)
+            || self.split_bytes.is_some_and(|split| self.part_size(part_len) >= split);
+        Ok(self.full && left > 0)
+    }
+
+    /// Estimated size of the current part in its output format.
+    fn part_size(&self, len: usize) -> u64 {
+        let num_ch = WAVE_BUFFS_NUM as u64;
+        let bytes_per_sample = match self.path.extension().and_then(|e| e.to_str()) {
+            Some("edf") => 2 * num_ch,
+            Some("bdf") => 3 * (num_ch + 1),
+            Some("vhdr") => 4 * num_ch,
+            Some("npz") => 5 * num_ch + 1,
+            Some("ngr") => 4 * num_ch,
+            _ => return self.out.get_ref().metadata().map_or(0, |m| m.len()),
+        };
+        len as u64 * bytes_per_sample
+    }
+
+    /// Finishes the current part and starts the next one, that begins with
+    /// the next sample in the buffers.
+    fn next_part(&mut self) -> io::Result<()> {
+        let first_sample = self.written.load(Ordering::SeqCst);
+        let first_time = RECORDING_TIMES.read().unwrap().first().copied();
+
+        let (path, info, annotations) = {
+            let current = self.current.lock().unwrap();
+            let len = first_sample - current.info.first_sample;
+            let annotations = part_annotations(&current.annotations, &current.info, Some(len));
+            (current.path.clone(), current.info.clone(), annotations)
+        };
+        finalize(&path, info.clone(), annotations)?;
+
+        let mut next = info;
+        let number = next.part.unwrap_or(1) + 1;
+        next.part = Some(number);
+        next.first_sample = first_sample;
+        if let Some(time) = first_time.and_then(|t| Local.timestamp_micros(t).single()) {
+            next.start = time;
+        }
+        let next_path = split_path(&self.path, number);
+        self.out = open_part(&next_path, &next, &self.opts)?;
+
+        let mut current = self.current.lock().unwrap();
+        current.path = next_path;
+        current.info = next;
+        self.full = false;
+        Ok(())
+    }
+}
+
+/// Creates the part file of `path` with the CSV header, its journal and its
+/// sidecar.
+fn open_part(path: &Path, info: &RecordingInfo, opts: &CsvOptions) -> io::Result<BufWriter<File>> {
+    let file = File::create(part_path(path))?;
+    let mut out = BufWriter::new(file);
+    csv::write_header(&mut out, info.labels.len(), opts)?;
+    out.flush()?;
+    out.get_ref().sync_data()?;
+
+    write_journal(path, info, &[])?;
+    meta::write_sidecar(path, info, &[])?;
+    Ok(out)
+}
+
+/// Annotations of the part of the recording described by `info` with `len`
+/// samples (`None` for the last part), with onsets from the part start.
+fn part_annotations(annotations: &[Annotation], info: &RecordingInfo, len: Option<usize>) -> Vec<Annotation> {
+    let fs = info.sampling_rate as f64;
+    let first = info.first_sample as f64 / fs;
+    let end = len.map_or(f64::INFINITY, |len| (info.first_sample + len) as f64 / fs);
+    annotations
+        .iter()
+        .filter(|a| a.onset >= first && a.onset < end)
+        .map(|a| Annotation { onset: a.onset - first, ..a.clone() })
+        .collect()
+}
+
+/// Path of part `number` of a split recording: `rest.edf` -> `rest_part-001.edf`.
+pub fn split_path(path: &Path, number: u32) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}_part-{:03}.{}", stem, number, ext.to_string_lossy()),
+        None => format!("{}_part-{:03}", stem, number),
+    };
+    path.with_file_name(name)
+}
+
+fn write_journal(path: &Path, info: &RecordingInfo, annotations: &[Annotation]) -> io::Result<()> {
+    let journal = object! {
+        path: path.to_string_lossy().to_string(),
+        info: info.to_json(),
+        annotations: annotations.iter().map(Annotation::to_json).collect::<Vec<_>>(),
+    };
+    fs::write(journal_path(path), journal.dump())
+}
+
+/// Turns the part file of `path` into the output file, writes its integrity
+/// summary and removes the journal. Lost samples are reported with `log_err`.
+fn finalize(path: &Path, info: RecordingInfo, annotations: Vec<Annotation>) -> io::Result<()> {
+    let part = part_path(path);
+    meta::write_sidecar(path, &info, &annotations)?;
+    let mut rec = csv::read_csv(&part, info)?;
+    rec.annotations = annotations;
+    if path.extension().is_some_and(|e| e == "csv") {
+        fs::rename(&part, path)?;
+    } else {
+        export(path, &rec)?;
+        fs::remove_file(&part)?;
+    }
+
+    let summary = integrity::save_summary(path, &rec)?;
+    if summary.is_incomplete() {
+        let lost: f64 = summary.gaps.iter().map(|g| g.duration).sum();
+        log_err(format!(
+            "{}: {} samples recorded, {} expected from the elapsed time, {} gaps ({:.3} s lost)",
+            path.display(),
+            summary.num_samples,
+            summary.expected_samples.unwrap_or(summary.num_samples),
+            summary.gaps.len(),
+            lost
+        ));
+    }
+    fs::remove_file(journal_path(path))
+}
+
+/// A recording that was not stopped cleanly, as found in the journal.
+pub struct Unfinished {
+    pub path: PathBuf,
+    pub info: RecordingInfo,
+    pub annotations: Vec<Annotation>,
+}
+
+/// Returns the recording to `path` that was not stopped cleanly, if it has
+/// a journal.
+pub fn unfinished(path: &Path) -> Option<Unfinished> {
+    let journal = json::parse(&fs::read_to_string(journal_path(path)).ok()?).ok()?;
+    Some(Unfinished {
+        path: PathBuf::from(journal["path"].as_str()?),
+        info: RecordingInfo::from_json(&journal["info"])?,
+        annotations: journal["annotations"]
+            .members()
+            .filter_map(Annotation::from_json)
+            .collect(),
+    })
+}
+
+/// Writes the output file of an unfinished recording from its part file.
+/// An incomplete last line (the write was interrupted) is dropped.
+pub fn recover(rec: Unfinished) -> io::Result<()> {
+    let part = part_path(&rec.path);
+    let mut data = fs::read(&part)?;
+    let end = data.iter().rposition(|b| *b == b'\n').map_or(0, |i| i + 1);
+    data.truncate(end);
+    fs::write(&part, data)?;
+    finalize(&rec.path, rec.info, rec.annotations)
+}
+
+/// Deletes the part file and the journal of an unfinished recording.
+pub fn discard(path: &Path) -> io::Result<()> {
+    let part = part_path(path);
+    if part.exists() {
+        fs::remove_file(part)?;
+    }
+    fs::remove_file(journal_path(path))
+}
+
+/// Path of the journal of the recording to `path`: `path` + `.journal`.
+pub fn journal_path(path: &Path) -> PathBuf {
+    let mut journal = OsString::from(path.as_os_str());
+    journal.push(".journal");
+    PathBuf::from(journal)
+}
+
+/// Path of the file the data is written to while recording: `path` + `.part`.
+pub fn part_path(path: &Path) -> PathBuf {
+    let mut part = OsString::from(path.as_os_str());
+    part.push(".part");
+    PathBuf::from(part)
+}
@@ -0,0 +1,636 @@
+//! Offline viewer of recordings. It opens any of the formats NiGUI writes
+//! and shows the channels on a scrollable and zoomable time axis, together
+//! with the spectrum of the visible window and an overview of the whole
+//! recording.
+//!
+//! Time spans of the recording can be annotated (bad segments, artifacts,
+//! notes...). Annotations are saved to the sidecar and the events file of the
+//! recording, see `record::meta`.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use eframe::egui::{self, Button, Color32, RichText, Sense, Stroke, Vec2};
+use egui_plot::{Bar, BarChart, Line, LineStyle, Plot, PlotBounds, PlotPoint, PlotPoints, Polygon, Text, VLine};
+use egui_plot::PlotResponse;
+use rfd::FileDialog;
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+
+use crate::log_err;
+use crate::record::{self, bids, meta, naming, Annotation, Recording};
+use crate::wave::psd::Psd;
+use crate::wave::{band_labels, BANDS, CHANNEL_COLORS, PSD_OPTIONS};
+
+/// Number of points of each channel in the overview.
+const MINIMAP_POINTS: usize = 2000;
+/// Highest frequency shown in the spectra, in Hz.
+const MAX_FREQ: f64 = 60.0;
+/// Width of the bars of the spectra, in Hz. Bins of long windows are averaged.
+const MIN_BAR_WIDTH: f64 = 0.25;
+/// Window lengths offered in the toolbar, in seconds.
+const WINDOW_LENGTHS: [f64; 7] = [1.0, 2.0, 5.0, 10.0, 20.0, 30.0, 60.0];
+
+const LEAD_OFF_COLOR: Color32 = Color32::from_rgba_premultiplied(120, 0, 0, 90);
+const VIEW_COLOR: Color32 = Color32::from_rgba_premultiplied(80, 80, 80, 60);
+const BAD_COLOR: Color32 = Color32::from_rgba_premultiplied(130, 70, 0, 90);
+const ANNOTATION_COLOR: Color32 = Color32::from_rgba_premultiplied(0, 60, 130, 90);
+
+pub struct Viewer {
+    path: Option<PathBuf>,
+    rec: Option<Recording>,
+    /// Start and length of the visible window, in seconds
+    start: f64,
+    length: f64,
+    /// Decimated (time, value) points of each channel for the overview,
+    /// normalized and stacked
+    minimap: Vec<Vec<[f64; 2]>>,
+    spectra: Option<Spectra>,
+    planner: FftPlanner<f64>,
+    /// Dragging on the signals creates annotations instead of panning
+    annotating: bool,
+    /// Label of the new annotations
+    new_label: String,
+    /// Span being selected to create an annotation, in seconds
+    selection: Option<(f64, f64)>,
+    /// Index of the annotation selected in the plots
+    selected: Option<usize>,
+    /// The annotations have unsaved changes
+    modified: bool,
+}
+
+/// Spectra of each channel in the visible window.
+struct Spectra {
+    /// Samples the spectra were computed for
+    range: (usize, usize),
+    bars: Vec<Vec<Bar>>,
+    /// PSD of each channel, for the band powers
+    psd: Psd,
+}
+
+impl Default for Viewer {
+    fn default() -> Self {
+        Self {
+            path: None,
+            rec: None,
+            start: 0.0,
+            length: 10.0,
+            minimap: vec![],
+            spectra: None,
+            planner: FftPlanner::new(),
+            annotating: false,
+            new_label: "BAD".into(),
+            selection: None,
+            selected: None,
+            modified: false,
+        }
+    }
+}
+
+impl Viewer {
+    pub fn show(&mut self, ctx: &egui::Context, open: &mut bool) {
+        egui::Window::new("Recording viewer")
+            .open(open)
+            .default_size([900.0, 650.0])
+            .resizable(true)
+            .show(ctx, |ui| {
+                self.toolbar(ui);
+                if self.rec.is_none() {
+                    ui.label("Open a recording (CSV, EDF+, BDF, BrainVision or NumPy) to view it.");
+                    return;
+                }
+                ui.separator();
+                self.minimap_plot(ui);
+                self.annotations_panel(ui);
+                ui.separator();
+                self.update_spectra();
+                self.channel_plots(ui);
+            });
+    }
+
+    fn open(&mut self) {
+        let extensions: Vec<&str> = naming::FORMATS.iter().map(|(_, ext)| *ext).collect();
+        let Some(path) = FileDialog::new().add_filter("Recordings", &extensions).pick_file() else {
+            return;
+        };
+        match record::load(&path) {
+            Ok(rec) => {
+                if self.modified {
+                    log_err("Unsaved annotations were discarded".into());
+                }
+                self.selection = None;
+                self.selected = None;
+                self.modified = false;
+                self.minimap = minimap(&rec);
+                self.start = 0.0;
+                self.length = self.length.min(rec.duration()).max(1.0);
+                self.spectra = None;
+                self.rec = Some(rec);
+                self.path = Some(path);
+            }
+            Err(e) => log_err(format!("Failed to open {}: {}", path.display(), e)),
+        }
+    }
+
+    fn toolbar(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            if ui.button("Open...").clicked() {
+                self.open();
+            }
+            let (Some(path), Some(rec)) = (&self.path, &self.rec) else { return };
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            ui.label(RichText::new(name).strong());
+            ui.label(format!(
+                "{:.1} s, {} Hz, {} marks",
+                rec.duration(),
+                rec.info.sampling_rate,
+                rec.events().len()
+            ));
+
+            ui.separator();
+            let duration = rec.duration();
+            if ui.button("⏪").on_hover_text("Previous window").clicked() {
+                self.start -= self.length;
+            }
+            if ui.button("⏩").on_hover_text("Next window").clicked() {
+                self.start += self.length;
+            }
+            ui.label("Window: ");
+            egui::ComboBox::from_id_source("viewer window")
+                .selected_text(format!("{} s", self.length))
+                .show_ui(ui, |ui| {
+                    for length in WINDOW_LENGTHS {
+                        ui.selectable_value(&mut self.length, length, format!("{} s", length));
+                    }
+                });
+            ui.label("Position: ");
+            let max_start = (duration - self.length).max(0.0);
+            ui.add(egui::Slider::new(&mut self.start, 0.0..=max_start).suffix(" s"));
+            self.clamp_view(duration);
+        });
+    }
+
+    /// Keeps the visible window inside the recording.
+    fn clamp_view(&mut self, duration: f64) {
+        self.length = self.length.clamp(0.1, duration.max(0.1));
+        self.start = self.start.clamp(0.0, (duration - self.length).max(0.0));
+    }
+
+    /// Overview of the whole recording. Clicking or dragging on it moves the
+    /// visible window, which is drawn as a shaded rectangle.
+    fn minimap_plot(&mut self, ui: &mut egui::Ui) {
+        let Some(rec) = &self.rec else { return };
+        let duration = rec.duration();
+        let num_ch = rec.channels.len() as f64;
+
+        let response = Plot::new("viewer minimap")
+            .height(80.0)
+            .allow_drag(false)
+            .allow_zoom(false)
+            .allow_scroll(false)
+            .allow_double_click_reset(false)
+            .show_axes([true, false])
+            .show_grid(false)
+            .show_y(false)
+            .show(ui, |plot_ui| {
+                plot_ui.set_plot_bounds(PlotBounds::from_min_max([0.0, -0.5], [duration, num_ch - 0.5]));
+                for (ch, points) in self.minimap.iter().enumerate() {
+                    let color = CHANNEL_COLORS[ch % CHANNEL_COLORS.len()];
+                    plot_ui.line(Line::new(PlotPoints::from(points.clone())).color(color));
+                }
+                for (ch, spans) in lead_off_spans(rec, 0, rec.len()).into_iter().enumerate() {
+                    let row = num_ch - 1.0 - ch as f64;
+                    for (start, end) in spans {
+                        plot_ui.polygon(span(start, end, row - 0.5, row + 0.5, LEAD_OFF_COLOR));
+                    }
+                }
+                for (sample, _) in rec.events() {
+                    let t = sample as f64 / rec.info.sampling_rate as f64;
+                    plot_ui.vline(VLine::new(t).color(Color32::WHITE).width(0.5));
+                }
+                plot_ui.polygon(
+                    span(self.start, self.start + self.length, -0.5, num_ch - 0.5, VIEW_COLOR)
+                        .stroke(Stroke::new(1.0, Color32::WHITE)),
+                );
+
+                let response = plot_ui.response();
+                if response.clicked() || response.dragged() {
+                    plot_ui.pointer_coordinate().map(|p| p.x)
+                } else {
+                    None
+                }
+            });
+
+        if let Some(t) = response.inner {
+            self.start = t - self.length / 2.0;
+            self.clamp_view(duration);
+        }
+    }
+
+    /// List of the annotations of the recording, where they can be edited.
+    fn annotations_panel(&mut self, ui: &mut egui::Ui) {
+        let Some(rec) = &mut self.rec else { return };
+        let title = format!("Annotations ({})", rec.annotations.len());
+        let duration = rec.duration();
+        let header = egui::CollapsingHeader::new(title).id_source("viewer annotations");
+        let go_to = header.show(ui, |ui| {
+            ui.horizontal(|ui| {
+                let annotate = Button::new("Annotate").selected(self.annotating);
+                if ui.add(annotate).on_hover_text("Drag on the signals to annotate a span").clicked() {
+                    self.annotating = !self.annotating;
+                }
+                ui.label("Label: ");
+                ui.add(egui::TextEdit::singleline(&mut self.new_label).desired_width(100.0))
+                    .on_hover_text("Labels starting with BAD mark segments that analyses skip");
+                let save = Button::new(if self.modified { "Save*" } else { "Save" });
+                if ui.add_enabled(self.modified, save).clicked() {
+                    rec.annotations.sort_by(|a, b| a.onset.total_cmp(&b.onset));
+                    self.selected = None;
+                    let path = self.path.as_deref().unwrap_or(Path::new(""));
+                    // BIDS recordings have their events in `*_events.tsv`
+                    let res = if bids::is_bids_recording(path) {
+                        bids::write_annotations(path, rec)
+                    } else {
+                        meta::write_sidecar(path, &rec.info, &rec.annotations)
+                            .and_then(|_| meta::write_events(&meta::events_path(path), rec))
+                    };
+                    match res {
+                        Ok(_) => self.modified = false,
+                        Err(e) => log_err(format!("Failed to save the annotations: {}", e)),
+                    }
+                }
+            });
+
+            let mut remove = None;
+            let mut go_to = None;
+            egui::ScrollArea::vertical().max_height(120.0).show(ui, |ui| {
+                egui::Grid::new("viewer annotations grid").striped(true).show(ui, |ui| {
+                    ui.label("");
+                    ui.label("Label");
+                    ui.label("Onset (s)");
+                    ui.label("Duration (s)");
+                    ui.label("Description");
+                    ui.end_row();
+
+                    for (i, annot) in rec.annotations.iter_mut().enumerate() {
+                        if ui.selectable_label(self.selected == Some(i), "▶").clicked() {
+                            self.selected = Some(i);
+                            go_to = Some(annot.onset);
+                        }
+                        let changed = [
+                            ui.add(egui::TextEdit::singleline(&mut annot.label).desired_width(100.0)),
+                            ui.add(egui::DragValue::new(&mut annot.onset).speed(0.01).clamp_range(0.0..=duration)),
+                            ui.add(egui::DragValue::new(&mut annot.duration).speed(0.01).clamp_range(0.0..=duration)),
+                            ui.add(egui::TextEdit::singleline(&mut annot.description).desired_width(200.0)),
+                        ]
+                        .iter()
+                        .any(|r| r.changed());
+                        self.modified |= changed;
+                        if ui.button("🗑").on_hover_text("Delete").clicked() {
+                            remove = Some(i);
+                        }
+                        ui.end_row();
+                    }
+                });
+            });
+
+            if let Some(i) = remove {
+                rec.annotations.remove(i);
+                self.selected = None;
+                self.modified = true;
+            }
+            go_to
+        });
+
+        if let Some(t) = go_to.body_returned.flatten() {
+            self.start = t - self.length / 4.0;
+            self.clamp_view(duration);
+        }
+    }
+
+    /// Recomputes the spectra if the visible window changed.
+    fn update_spectra(&mut self) {
+        let Some(rec) = &self.rec else { return };
+        let range = visible_range(rec, self.start, self.length);
+        if self.spectra.as_ref().is_some_and(|s| s.range == range) {
+            return;
+        }
+        let fft = self.planner.plan_fft_forward((range.1 - range.0).max(1));
+        let bars = (0..rec.channels.len())
+            .map(|ch| spectrum(rec, ch, range, &fft))
+            .collect();
+        let waves: Vec<Vec<f32>> = (0..rec.channels.len())
+            .map(|ch| rec.channel_uv(ch).skip(range.0).take(range.1 - range.0).map(|v| v as f32).collect())
+            .collect();
+        let psd = Psd::welch(&waves, rec.info.sampling_rate as f64, &PSD_OPTIONS.read().unwrap());
+        self.spectra = Some(Spectra { range, bars, psd });
+    }
+
+    /// One row per channel: the signal of the visible window with its marks,
+    /// annotations and lead-off periods, and its spectrum. Dragging on a
+    /// signal pans the window (or annotates it) and scrolling zooms it.
+    fn channel_plots(&mut self, ui: &mut egui::Ui) {
+        let Some(rec) = &self.rec else { return };
+        let Some(Spectra { range, bars: spectra, psd }) = &self.spectra else { return };
+        let fs = rec.info.sampling_rate as f64;
+        let duration = rec.duration();
+        let (start, length) = (self.start, self.length);
+        let num_ch = rec.channels.len();
+        let space = Vec2::new(ui.available_width(), (ui.available_height() / num_ch as f32 - 5.0).max(60.0));
+        let lead_off = lead_off_spans(rec, range.0, range.1);
+
+        let (annotating, selected) = (self.annotating, self.selected);
+        let mut selection = self.selection;
+        let mut created = None;
+        let mut clicked = None;
+        let mut pan = 0.0;
+        let mut zoom = None;
+        for ch in 0..num_ch {
+            let color = CHANNEL_COLORS[ch % CHANNEL_COLORS.len()];
+            let points = decimate(rec, ch, *range, 2 * space.x as usize);
+            let (min, max) = points
+                .iter()
+                .fold((f64::MAX, f64::MIN), |(min, max), p| (min.min(p[1]), max.max(p[1])));
+            let (min, max) = if min < max { (min, max) } else { (min - 1.0, max + 1.0) };
+            let margin = 0.05 * (max - min);
+            let (min, max) = (min - margin, max + margin);
+
+            let bars = &spectra[ch];
+            let bands = BANDS.read().unwrap().clone();
+            let band_mags: Vec<f64> = bands.iter().map(|b| b.share(&psd.channels[ch], psd.bin_size)).collect();
+
+            ui.allocate_ui(space, |ui| {
+                ui.columns(2, |columns| {
+                    columns[0].horizontal_top(|ui| {
+                        ui.vertical(|ui| {
+                            ui.horizontal(|ui| {
+                                let off = &lead_off[ch];
+                                let imp_stat = Button::new("")
+                                    .sense(Sense::hover())
+                                    .fill(if off.is_empty() { Color32::GREEN } else { Color32::RED });
+                                ui.add(imp_stat);
+                                let label = rec.info.labels.get(ch).cloned().unwrap_or_default();
+                                ui.label(RichText::new(label).strong());
+                            });
+                            band_labels(ui, ("viewer bands", ch), &bands, &band_mags);
+                        });
+
+                        let response = Plot::new(("viewer wave", ch))
+                            .allow_drag(false)
+                            .allow_zoom(false)
+                            .allow_scroll(false)
+                            .allow_double_click_reset(false)
+                            .link_cursor("viewer", true, false)
+                            .y_axis_width(5)
+                            .show(ui, |plot_ui| {
+                                plot_ui.set_plot_bounds(PlotBounds::from_min_max([start, min], [start + length, max]));
+                                for &(s0, s1) in lead_off[ch].iter() {
+                                    plot_ui.polygon(span(s0, s1, min, max, LEAD_OFF_COLOR));
+                                }
+                                plot_ui.line(Line::new(PlotPoints::from(points)).color(color));
+                                for (sample, code) in rec.events() {
+                                    let t = sample as f64 / fs;
+                                    if t >= start && t <= start + length {
+                                        plot_ui.vline(VLine::new(t).color(Color32::WHITE).name(rec.info.mark_label(code)));
+                                        if ch == 0 {
+                                            plot_ui.text(Text::new(PlotPoint::new(t, max), rec.info.mark_label(code)));
+                                        }
+                                    }
+                                }
+                                for (i, annot) in rec.annotations.iter().enumerate() {
+                                    let end = annot.onset + annot.duration;
+                                    if end < start || annot.onset > start + length {
+                                        continue;
+                                    }
+                                    let color = if annot.is_bad() { BAD_COLOR } else { ANNOTATION_COLOR };
+                                    let stroke = if selected == Some(i) {
+                                        Stroke::new(1.5, Color32::WHITE)
+                                    } else {
+                                        Stroke::NONE
+                                    };
+                                    if annot.duration > 0.0 {
+                                        let area = span(annot.onset, end, min, max, color).stroke(stroke).name(&annot.label);
+                                        plot_ui.polygon(area);
+                                    } else {
+                                        let line = VLine::new(annot.onset)
+                                            .color(Color32::GRAY)
+                                            .width(stroke.width.max(1.0))
+                                            .style(LineStyle::dashed_loose())
+                                            .name(&annot.label);
+                                        plot_ui.vline(line);
+                                    }
+                                    if ch == 0 {
+                                        plot_ui.text(Text::new(PlotPoint::new(annot.onset, min), &annot.label));
+                                    }
+                                }
+                                if let Some((s0, s1)) = selection {
+                                    plot_ui.polygon(span(s0.min(s1), s0.max(s1), min, max, VIEW_COLOR));
+                                }
+
+                                let pointer = plot_ui.pointer_coordinate().map(|p| p.x);
+                                (pointer, plot_ui.pointer_coordinate_drag_delta().x as f64)
+                            });
+
+                        let PlotResponse { response, inner: (pointer, drag_delta), .. } = response;
+                        if annotating && response.drag_started() {
+                            selection = pointer.map(|t| (t, t));
+                        } else if annotating && response.dragged() {
+                            if let (Some(sel), Some(t)) = (selection.as_mut(), pointer) {
+                                sel.1 = t.clamp(0.0, duration);
+                            }
+                        } else if annotating && response.drag_released() {
+                            created = selection.take();
+                        } else if response.dragged() {
+                            pan -= drag_delta;
+                        }
+                        if response.clicked() {
+                            clicked = pointer;
+                        }
+                        if response.hovered() {
+                            let scroll = ui.input(|i| i.smooth_scroll_delta.y);
+                            if scroll != 0.0 {
+                                zoom = Some(((-scroll as f64 / 200.0).exp(), pointer.unwrap_or(start)));
+                            }
+                        }
+                    });
+
+                    columns[1].horizontal_top(|ui| {
+                        let chart = BarChart::new(bars.clone()).color(color);
+                        Plot::new(("viewer spectrum", ch))
+                            .allow_drag(false)
+                            .allow_zoom(false)
+                            .allow_scroll(false)
+                            .show_y(false)
+                            .include_x(0.0)
+                            .include_x(MAX_FREQ)
+                            .include_y(0.0)
+                            .show(ui, |plot_ui| plot_ui.bar_chart(chart));
+                    });
+                });
+            });
+        }
+
+        self.selection = selection;
+        if let Some((s0, s1)) = created {
+            // shorter than a sample: an instant annotation
+            let duration = (s1 - s0).abs();
+            self.add_annotation(s0.min(s1), if duration * fs < 1.0 { 0.0 } else { duration });
+        }
+        if let Some(t) = clicked {
+            self.selected = self.annotation_at(t);
+        }
+
+        self.start += pan;
+        if let Some((factor, center)) = zoom {
+            let length = self.length * factor;
+            self.start = center - (center - self.start) * factor;
+            self.length = length;
+        }
+        self.clamp_view(duration);
+    }
+
+    fn add_annotation(&mut self, onset: f64, duration: f64) {
+        let Some(rec) = &mut self.rec else { return };
+        rec.annotations.push(Annotation {
+            onset,
+            duration,
+            label: self.new_label.trim().to_string(),
+            description: String::new(),
+        });
+        self.selected = Some(rec.annotations.len() - 1);
+        self.modified = true;
+    }
+
+    /// Index of the annotation at time `t`. Instant annotations are hit
+    /// within half a percent of the visible window.
+    fn annotation_at(&self, t: f64) -> Option<usize> {
+        let rec = self.rec.as_ref()?;
+        let tolerance = 0.005 * self.length;
+        rec.annotations
+            .iter()
+            .position(|a| t >= a.onset - tolerance && t <= a.onset + a.duration + tolerance)
+    }
+}
+
+/// Range of samples of the window starting at `start` seconds.
+fn visible_range(rec: &Recording, start: f64, length: f64) -> (usize, usize) {
+    let fs = rec.info.sampling_rate as f64;
+    let first = ((start * fs) as usize).min(rec.len());
+    let last = (((start + length) * fs).ceil() as usize).min(rec.len());
+    (first, last.max(first))
+}
+
+/// Returns the (time, µV) points of a channel in the samples `range`. If
+/// there are more than `max_points`, each group of samples is replaced by
+/// its minimum and maximum, so that peaks remain visible.
+fn decimate(rec: &Recording, ch: usize, range: (usize, usize), max_points: usize) -> Vec<[f64; 2]> {
+    let fs = rec.info.sampling_rate as f64;
+    let samples: Vec<f64> = rec.channel_uv(ch).skip(range.0).take(range.1 - range.0).collect();
+    let group = samples.len().div_ceil(max_points.max(2) / 2).max(1);
+    if group == 1 {
+        return samples
+            .iter()
+            .enumerate()
+            .map(|(i, v)| [(range.0 + i) as f64 / fs, *v])
+            .collect();
+    }
+
+    let mut points = Vec::with_capacity(2 * samples.len() / group + 2);
+    for (g, chunk) in samples.chunks(group).enumerate() {
+        let t = (range.0 + g * group) as f64 / fs;
+        let (min, max) = chunk
+            .iter()
+            .fold((f64::MAX, f64::MIN), |(min, max), v| (min.min(*v), max.max(*v)));
+        points.push([t, min]);
+        points.push([t, max]);
+    }
+    points
+}
+
+/// Points of the overview: each channel decimated, scaled to its robust range
+/// and placed at height `ch`.
+fn minimap(rec: &Recording) -> Vec<Vec<[f64; 2]>> {
+    (0..rec.channels.len())
+        .map(|ch| {
+            let mut points = decimate(rec, ch, (0, rec.len()), MINIMAP_POINTS);
+            let mut values: Vec<f64> = points.iter().map(|p| p[1]).collect();
+            values.sort_by(f64::total_cmp);
+            let (low, high) = match values.len() {
+                0 => (0.0, 1.0),
+                n => (values[n / 20], values[n - 1 - n / 20]),
+            };
+            let scale = if high > low { 0.8 / (high - low) } else { 1.0 };
+            let mid = (high + low) / 2.0;
+            let top = (rec.channels.len() - 1 - ch) as f64;
+            for p in points.iter_mut() {
+                p[1] = top + ((p[1] - mid) * scale).clamp(-0.45, 0.45);
+            }
+            points
+        })
+        .collect()
+}
+
+/// Periods (start, end in seconds) of each channel with the electrode
+/// disconnected, within the samples `first..last`.
+fn lead_off_spans(rec: &Recording, first: usize, last: usize) -> Vec<Vec<(f64, f64)>> {
+    let fs = rec.info.sampling_rate as f64;
+    rec.status
+        .iter()
+        .map(|status| {
+            let mut spans = vec![];
+            let mut off_since = None;
+            for (s, ok) in status.iter().enumerate().take(last).skip(first) {
+                match (ok, off_since) {
+                    (false, None) => off_since = Some(s),
+                    (true, Some(s0)) => {
+                        spans.push((s0 as f64 / fs, s as f64 / fs));
+                        off_since = None;
+                    }
+                    _ => (),
+                }
+            }
+            if let Some(s0) = off_since {
+                spans.push((s0 as f64 / fs, last as f64 / fs));
+            }
+            spans
+        })
+        .collect()
+}
+
+/// Shaded rectangle between two times.
+fn span(start: f64, end: f64, min: f64, max: f64, color: Color32) -> Polygon {
+    let points = vec![[start, min], [end, min], [end, max], [start, max]];
+    Polygon::new(PlotPoints::from(points))
+        .fill_color(color)
+        .stroke(Stroke::NONE)
+}
+
+/// Magnitude spectrum (µV) of a channel in the samples `range`, up to
+/// `MAX_FREQ`. Consecutive bins are averaged so that bars are at least
+/// `MIN_BAR_WIDTH` wide.
+fn spectrum(rec: &Recording, ch: usize, range: (usize, usize), fft: &Arc<dyn Fft<f64>>) -> Vec<Bar> {
+    let n = range.1 - range.0;
+    if n < 2 {
+        return vec![];
+    }
+    let samples: Vec<f64> = rec.channel_uv(ch).skip(range.0).take(n).collect();
+    let mean = samples.iter().sum::<f64>() / n as f64;
+    let mut buffer: Vec<Complex<f64>> = samples.iter().map(|v| Complex::new(v - mean, 0.0)).collect();
+    fft.process(&mut buffer);
+
+    let bin_size = rec.info.sampling_rate as f64 / n as f64;
+    let group = (MIN_BAR_WIDTH / bin_size).ceil().max(1.0) as usize;
+    let scale = 1.0 / (n as f64).sqrt();
+    buffer[..n / 2]
+        .chunks(group)
+        .enumerate()
+        .map(|(i, bins)| {
+            let freq = (i * group) as f64 * bin_size;
+            let mag = bins.iter().map(|c| c.norm()).sum::<f64>() / bins.len() as f64 * scale;
+            Bar::new(freq, mag)
+                .width(0.95 * group as f64 * bin_size)
+                .name(format!("{:.2} Hz", freq))
+        })
+        .take_while(|bar| bar.argument <= MAX_FREQ)
+        .collect()
+}
@@ -0,0 +1,173 @@
+//! Frequency bands of the band power charts. The list is edited in the
+//! settings and shared by the live plots and the viewer through `BANDS`.
+//! It is saved in `BANDS_FILE` of the configuration directory and restored
+//! on start.
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use eframe::egui::{self, Color32, DragValue};
+use json::{object, JsonValue};
+
+use super::*;
+use crate::{config_dir, log_err};
+
+/// Highest frequency of the bands, the spectra are shown up to 60 Hz.
+pub const MAX_FREQ: f64 = 60.0;
+
+pub const BANDS_FILE: &str = "bands.json";
+
+/// `BANDS` was edited and not saved yet.
+static UNSAVED: AtomicBool = AtomicBool::new(false);
+
+/// Frequency band, from `low` (included) to `high` (excluded), in Hz.
+#[derive(Clone, PartialEq)]
+pub struct Band {
+    pub name: String,
+    pub low: f64,
+    pub high: f64,
+    pub color: Color32,
+}
+
+impl Band {
+    fn new(name: &str, low: f64, high: f64, color: Color32) -> Self {
+        Band { name: name.into(), low, high, color }
+    }
+
+    /// Power (µV²) of the band in a PSD (µV²/Hz) with values every
+    /// `bin_size` Hz from 0 Hz: the sum of the bins in the band.
+    pub fn psd_power(&self, psd: &[f32], bin_size: f64) -> f64 {
+        psd.iter()
+            .enumerate()
+            .filter(|(i, _)| (self.low..self.high).contains(&(*i as f64 * bin_size)))
+            .map(|(_, v)| *v as f64 * bin_size)
+            .sum()
+    }
+
+    /// Share (%) of the band in the power of a PSD up to `MAX_FREQ`, the
+    /// band power shown next to the spectra and in the trends.
+    pub fn share(&self, psd: &[f32], bin_size: f64) -> f64 {
+        let total: f64 = psd
+            .iter()
+            .enumerate()
+            .take_while(|(i, _)| *i as f64 * bin_size <= MAX_FREQ)
+            .map(|(_, v)| *v as f64 * bin_size)
+            .sum();
+        if total > 0.0 { 100.0 * self.psd_power(psd, bin_size) / total } else { 0.0 }
+    }
+
+    fn to_json(&self) -> JsonValue {
+        object! {
+            name: self.name.as_str(),
+            low: self.low,
+            high: self.high,
+            color: [self.color.r(), self.color.g(), self.color.b()],
+        }
+    }
+
+    fn from_json(value: &JsonValue) -> Option<Self> {
+        let color: Vec<u8> = value["color"].members().filter_map(JsonValue::as_u8).collect();
+        let [r, g, b] = color[..] else { return None };
+        Some(Band {
+            name: value["name"].as_str()?.into(),
+            low: value["low"].as_f64()?,
+            high: value["high"].as_f64()?,
+            color: Color32::from_rgb(r, g, b),
+        })
+    }
+}
+
+fn bands_path() -> PathBuf {
+    config_dir().join(BANDS_FILE)
+}
+
+/// Bands saved in `BANDS_FILE`, the default ones if there are none.
+pub fn load() -> Vec<Band> {
+    let Some(value) = fs::read_to_string(bands_path()).ok().and_then(|text| json::parse(&text).ok()) else {
+        return default_bands();
+    };
+    value.members().filter_map(Band::from_json).collect()
+}
+
+fn save(bands: &[Band]) -> io::Result<()> {
+    let path = bands_path();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let value: Vec<JsonValue> = bands.iter().map(Band::to_json).collect();
+    fs::write(path, JsonValue::from(value).pretty(4))
+}
+
+/// Saves `BANDS` if it was edited since the last save.
+pub fn save_edits() {
+    if UNSAVED.swap(false, Ordering::Relaxed) {
+        if let Err(e) = save(&BANDS.read().unwrap()) {
+            log_err(format!("Failed to save the frequency bands: {}", e));
+        }
+    }
+}
+
+/// Classic EEG bands, contiguous from 0.5 to 45 Hz.
+pub fn default_bands() -> Vec<Band> {
+    vec![
+        Band::new("delta", 0.5, 4.0, Color32::from_rgb(100, 149, 237)),
+        Band::new("theta", 4.0, 8.0, Color32::from_rgb(0, 206, 209)),
+        Band::new("alpha", 8.0, 13.0, Color32::from_rgb(50, 205, 50)),
+        Band::new("beta", 13.0, 30.0, Color32::from_rgb(255, 215, 0)),
+        Band::new("gamma", 30.0, 45.0, Color32::from_rgb(255, 99, 71)),
+    ]
+}
+
+/// Editor of `BANDS`: name, limits and color of each band. The plots follow
+/// the edits right away, they are saved when a field is released, or by
+/// `save_edits` when the editor is closed.
+pub fn edit(ui: &mut egui::Ui) {
+    let mut bands = BANDS.read().unwrap().clone();
+    let mut remove = None;
+    let mut done = false;
+    egui::Grid::new("bands editor").num_columns(5).show(ui, |ui| {
+        for (i, band) in bands.iter_mut().enumerate() {
+            let fields = [
+                ui.add(egui::TextEdit::singleline(&mut band.name).desired_width(80.0)),
+                ui.add(DragValue::new(&mut band.low).clamp_range(0.0..=MAX_FREQ).speed(0.1).suffix(" Hz")),
+                ui.add(DragValue::new(&mut band.high).clamp_range(0.0..=MAX_FREQ).speed(0.1).suffix(" Hz")),
+            ];
+            done |= fields.iter().any(|r| r.drag_released() || r.lost_focus());
+            ui.color_edit_button_srgba(&mut band.color);
+            if ui.button("🗑").on_hover_text("Remove the band").clicked() {
+                remove = Some(i);
+                done = true;
+            }
+            ui.end_row();
+            band.high = band.high.max(band.low);
+        }
+    });
+    if let Some(i) = remove {
+        bands.remove(i);
+    }
+    ui.horizontal(|ui| {
+        if ui.button("Add band").clicked() {
+            let low = bands.last().map_or(0.0, |b| b.high).min(MAX_FREQ - 1.0);
+            let color = CHANNEL_COLORS[bands.len() % CHANNEL_COLORS.len()];
+            bands.push(Band::new(&format!("band {}", bands.len() + 1), low, low + 1.0, color));
+            done = true;
+        }
+        if ui.button("Reset").clicked() {
+            bands = default_bands();
+            done = true;
+        }
+    });
+
+    {
+        let mut current = BANDS.write().unwrap();
+        if *current != bands {
+            UNSAVED.store(true, Ordering::Relaxed);
+            *current = bands;
+        }
+    }
+    if done {
+        save_edits();
+    }
+}
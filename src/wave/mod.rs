@@ -1,12 +1,18 @@
+use chrono::{DateTime, Local};
 use rustfft::{Fft, FftPlanner};
+use std::collections::VecDeque;
 use std::sync::RwLock;
 
 use std::sync::Arc;
 
+pub mod bands;
 mod plot;
+pub mod psd;
 pub mod read;
+pub mod spectrogram;
+pub mod trends;
 
-pub use plot::plot_waves;
+pub use plot::{band_labels, live_controls, plot_waves, Layout, LiveView, CHANNEL_COLORS};
 
 /// The number of wave points to store. Buffers are circular,
 /// hence, once the limit of the buffer is reached, data points get
@@ -17,6 +23,12 @@ pub const WAVE_BUFF_LEN: usize = 1024;
 pub const WAVE_BUFFS_NUM: usize = 6;
 /// Sampling rate of the NAPSE board
 pub const SAMPLING_RATE: u32 = 250;
+/// Input range of the NAPSE ADC in µV: a normalized sample of 1.0 corresponds
+/// to `FULL_SCALE_UV` at the electrode (4.5 V reference with a PGA gain of 24).
+pub const FULL_SCALE_UV: f64 = 187_500.0;
+/// Seconds of wave data kept to scroll back the live plots.
+pub const HISTORY_SECS: usize = 600;
+pub const HISTORY_LEN: usize = HISTORY_SECS * SAMPLING_RATE as usize;
 
 lazy_static! {
     /// This list contains the (circular) buffers that store the wave data.
@@ -41,13 +53,113 @@ lazy_static! {
 
     pub static ref FFT_SCALE: f32 = 1.0 / (WAVE_BUFF_LEN as f32).sqrt();
 
+    /// Longer history of the waves in `WAVE_BUFFS`, to scroll back the live
+    /// plots. Filled at the same pace, independently of the recording.
+    pub static ref HISTORY : RwLock<History> = RwLock::new(History::new());
+
+    /// Frequency bands of the band power charts.
+    pub static ref BANDS : RwLock<Vec<bands::Band>> = RwLock::new(bands::load());
+
+    /// Settings of the Welch PSD shown in the live plots.
+    pub static ref PSD_OPTIONS : RwLock<psd::PsdOptions> = RwLock::new(psd::PsdOptions::default());
+
+    /// Welch PSD of `WAVE_BUFFS`, updated with `FFT_BUFFS`.
+    pub static ref PSD : RwLock<psd::Psd> = {
+        let waves = [[0f32; WAVE_BUFF_LEN]; WAVE_BUFFS_NUM];
+        RwLock::new(psd::Psd::compute(&waves, &psd::PsdOptions::default()))
+    };
+
+    /// Spectra of `FFT_BUFFS` over time, for the spectrograms.
+    pub static ref SPECTROGRAM : RwLock<spectrogram::Spectrogram> = RwLock::new(spectrogram::Spectrogram::new());
+
+    /// Power spectra of `FFT_BUFFS` over time, for the band power trends.
+    pub static ref TRENDS : RwLock<trends::Trends> = RwLock::new(trends::Trends::new());
+
     pub static ref RECORDING_BUFFS : RwLock<Vec<Vec<f32>>> = {
         let values = vec![vec![]];
         RwLock::new(values)
     };
 
+    /// Host wall-clock time (µs since the Unix epoch) at which each of the
+    /// samples in `RECORDING_BUFFS` was received.
+    pub static ref RECORDING_TIMES : RwLock<Vec<i64>> = RwLock::new(vec![]);
+
     pub static ref RECORDING_FLAG : RwLock<bool> = {
         RwLock::new(false)
     };
 
 }
+
+/// Last `HISTORY_LEN` samples of each wave, indexed by the number of samples
+/// received since the start so that a position stays valid while new
+/// samples arrive.
+pub struct History {
+    buffs: Vec<VecDeque<f32>>,
+    /// Marks of the samples kept, oldest first
+    marks: VecDeque<LiveMark>,
+    /// Index of the next sample
+    end: usize,
+}
+
+/// Mark received from the NAPSE.
+#[derive(Clone, Copy)]
+pub struct LiveMark {
+    /// Index of the sample in `HISTORY`
+    pub sample: usize,
+    pub code: u8,
+    /// Host time the mark arrived at
+    pub time: DateTime<Local>,
+}
+
+impl History {
+    fn new() -> Self {
+        History {
+            buffs: vec![VecDeque::with_capacity(HISTORY_LEN); WAVE_BUFFS_NUM],
+            marks: VecDeque::new(),
+            end: 0,
+        }
+    }
+
+    /// Appends one sample of each wave, dropping the oldest ones once full.
+    pub fn push(&mut self, values: &[f32]) {
+        for (buff, v) in self.buffs.iter_mut().zip(values) {
+            if buff.len() == HISTORY_LEN {
+                buff.pop_front();
+            }
+            buff.push_back(*v);
+        }
+        self.end += 1;
+        while self.marks.front().is_some_and(|m| m.sample < self.start()) {
+            self.marks.pop_front();
+        }
+    }
+
+    /// Adds a mark at the last sample.
+    pub fn push_mark(&mut self, code: u8, time: DateTime<Local>) {
+        let sample = self.end.saturating_sub(1);
+        self.marks.push_back(LiveMark { sample, code, time });
+    }
+
+    /// Marks of the samples kept, oldest first.
+    pub fn marks(&self) -> impl DoubleEndedIterator<Item = &LiveMark> + ExactSizeIterator {
+        self.marks.iter()
+    }
+
+    /// Index of the oldest sample kept.
+    pub fn start(&self) -> usize {
+        self.end - self.buffs[0].len()
+    }
+
+    /// Index of the next sample.
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    /// Samples of wave `ch` with indexes in `start..end`, clamped to the kept ones.
+    pub fn range(&self, ch: usize, start: usize, end: usize) -> impl Iterator<Item = f32> + '_ {
+        let first = self.start();
+        let from = start.clamp(first, self.end) - first;
+        let to = end.clamp(first, self.end) - first;
+        self.buffs[ch].range(from..to.max(from)).copied()
+    }
+}
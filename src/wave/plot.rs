@@ -1,77 +1,490 @@
-use eframe::egui::{self, Color32, RichText, Button, Sense, Vec2};
-use egui_plot::{PlotPoint, BarChart, Bar, Legend, Line, Plot, PlotPoints, Text};
+use eframe::egui::{self, Color32, DragValue, RichText, Button, Sense, Slider, Vec2};
+use egui_plot::{uniform_grid_spacer, GridMark, PlotPoint, BarChart, Bar, Legend, Line, Plot, PlotBounds, PlotPoints, PlotUi, Points, Text, VLine};
 
 use crate::wifi::CH_STATUS;
 
 use super::*;
+use super::psd::{self, Averaging, Psd};
+use super::spectrogram::SpectrogramView;
+use super::trends::TrendView;
+
+/// Colors of the channels in the plots.
+pub const CHANNEL_COLORS: [Color32; 6] = [
+    Color32::from_rgb(255, 59, 71),   // tomato
+    Color32::from_rgb(135, 206, 235), // sky blue
+    Color32::from_rgb(0, 255, 127),   // SpringGreen
+    Color32::from_rgb(106, 90, 205),  // SlateBlue
+    Color32::from_rgb(255, 255, 153),  // Dav-id Yellow
+    Color32::from_rgb(255, 139, 255),  // NIT Pink
+];
+
+/// Shortest and longest time shown in the raw plots, in seconds.
+pub const MIN_WINDOW: f64 = 1.0;
+pub const MAX_WINDOW: f64 = 60.0;
+
+/// Points drawn per raw plot at most. Longer windows are decimated.
+const MAX_POINTS: usize = 2000;
+
+/// Sensitivity presets of the raw plots, in µV per division.
+pub const SENSITIVITIES: [f64; 18] = [
+    1.0, 2.0, 3.0, 5.0, 7.0, 10.0, 15.0, 20.0, 30.0, 50.0, 70.0, 100.0, 150.0, 200.0, 500.0, 1000.0,
+    2000.0, 5000.0,
+];
+/// Vertical divisions of a raw plot with a fixed sensitivity.
+const DIVISIONS: f64 = 4.0;
+/// Range of the global and channel gains.
+const MIN_GAIN: f64 = 0.1;
+const MAX_GAIN: f64 = 10.0;
+/// Color of the marks in the raw plots.
+const MARK_COLOR: Color32 = Color32::from_rgb(255, 165, 0);
+
+/// Fraction of the ADC full scale from which a channel is reported as
+/// saturated.
+const ADC_SATURATION: f64 = 0.99;
+
+/// How the raw plots are laid out.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Layout {
+    /// One row per channel, with its FFT and frequency bands
+    Rows,
+    /// All channels in a single plot, one above the other on a shared time
+    /// axis, as in clinical viewers
+    Stacked,
+}
+
+/// Time shown in the raw plots and whether they follow the incoming data.
+pub struct LiveView {
+    pub layout: Layout,
+    /// Seconds shown, from `MIN_WINDOW` to `MAX_WINDOW`
+    pub window: f64,
+    /// Index in `HISTORY` after the last sample shown when the display is
+    /// frozen. `None` follows the incoming data.
+    end: Option<usize>,
+    /// PSD at the time the display was frozen
+    frozen_psd: Option<Psd>,
+    /// Fit each raw plot to its data instead of using the sensitivity
+    pub autoscale: bool,
+    /// µV per division at a gain of 1, one of `SENSITIVITIES`
+    pub sensitivity: f64,
+    /// Gain applied to every channel, on top of `channel_gains`
+    pub gain: f64,
+    pub channel_gains: [f64; WAVE_BUFFS_NUM],
+    /// Show the PSD in dB instead of µV²/Hz
+    pub psd_db: bool,
+    /// Draw the PSD as a line instead of bars
+    pub psd_line: bool,
+    /// Show spectrograms instead of the latest spectra, in the rows layout
+    pub show_spectrogram: bool,
+    pub spectrogram: SpectrogramView,
+    /// Labels of the marks, from code 1
+    pub mark_labels: Vec<String>,
+    /// Show the window with the recent marks
+    pub show_marks: bool,
+    /// Show the window with the band power trends
+    pub show_trends: bool,
+    pub trends: TrendView,
+}
+
+impl Default for LiveView {
+    fn default() -> Self {
+        LiveView {
+            layout: Layout::Rows,
+            // about the length of `WAVE_BUFFS`
+            window: 4.0,
+            end: None,
+            frozen_psd: None,
+            autoscale: false,
+            sensitivity: 50.0,
+            gain: 1.0,
+            channel_gains: [1.0; WAVE_BUFFS_NUM],
+            psd_db: true,
+            psd_line: false,
+            show_spectrogram: false,
+            spectrogram: SpectrogramView::default(),
+            mark_labels: vec![],
+            show_marks: false,
+            show_trends: false,
+            trends: TrendView::default(),
+        }
+    }
+}
+
+impl LiveView {
+    pub fn is_frozen(&self) -> bool {
+        self.end.is_some()
+    }
+
+    /// Stops updating the plots. Acquisition and recording go on.
+    pub fn freeze(&mut self) {
+        self.end = Some(HISTORY.read().unwrap().end());
+        self.frozen_psd = Some(PSD.read().unwrap().clone());
+    }
+
+    /// Goes back to showing the incoming data.
+    pub fn follow(&mut self) {
+        self.end = None;
+        self.frozen_psd = None;
+    }
+
+    /// Code of a mark followed by its label, if it has one.
+    fn mark_text(&self, code: u8) -> String {
+        match self.mark_labels.get(code as usize - 1) {
+            Some(label) if !label.trim().is_empty() => format!("{} {}", code, label.trim()),
+            _ => code.to_string(),
+        }
+    }
+
+    /// µV per division of channel `ch`, with the gains applied.
+    pub fn channel_sensitivity(&self, ch: usize) -> f64 {
+        self.sensitivity / (self.gain * self.channel_gains[ch])
+    }
+
+    /// Moves the shown time by `seconds`, negative to go back. Freezes the
+    /// display so that it doesn't move under the pointer.
+    fn scroll(&mut self, seconds: f64) {
+        if !self.is_frozen() {
+            self.freeze();
+        }
+        let history = HISTORY.read().unwrap();
+        let window = (self.window * SAMPLING_RATE as f64) as usize;
+        let earliest = (history.start() + window).min(history.end());
+        let end = self.end.unwrap_or(history.end()) as f64 + seconds * SAMPLING_RATE as f64;
+        self.end = Some((end.max(0.0) as usize).clamp(earliest, history.end()));
+    }
+}
+
+/// Controls of the raw plots: length of the window, amplitude scale, freeze
+/// toggle and the position in the history while frozen.
+pub fn live_controls(ui: &mut egui::Ui, view: &mut LiveView) {
+    ui.horizontal(|ui| {
+        ui.selectable_value(&mut view.layout, Layout::Rows, "Rows");
+        ui.selectable_value(&mut view.layout, Layout::Stacked, "Stacked");
+        ui.separator();
+        ui.label("Window: ");
+        ui.add(Slider::new(&mut view.window, MIN_WINDOW..=MAX_WINDOW).logarithmic(true).suffix(" s"));
+
+        ui.separator();
+        ui.label("Sensitivity: ");
+        ui.add_enabled_ui(!view.autoscale, |ui| {
+            egui::ComboBox::from_id_source("live sensitivity")
+                .selected_text(format!("{} µV/div", view.sensitivity))
+                .show_ui(ui, |ui| {
+                    for sensitivity in SENSITIVITIES {
+                        ui.selectable_value(&mut view.sensitivity, sensitivity, format!("{} µV/div", sensitivity));
+                    }
+                });
+            ui.label("Gain: ");
+            ui.add(DragValue::new(&mut view.gain).clamp_range(MIN_GAIN..=MAX_GAIN).speed(0.05).prefix("×"));
+            if ui.button("Reset gains").clicked() {
+                view.gain = 1.0;
+                view.channel_gains = [1.0; WAVE_BUFFS_NUM];
+            }
+        });
+        ui.checkbox(&mut view.autoscale, "Autoscale");
+        ui.separator();
+
+        let frozen = view.is_frozen();
+        if ui.add(Button::new("❄ Freeze display").selected(frozen)).clicked() {
+            if frozen {
+                view.follow();
+            } else {
+                view.freeze();
+            }
+        }
+
+        if let Some(end) = view.end {
+            let (start, now) = {
+                let history = HISTORY.read().unwrap();
+                (history.start(), history.end())
+            };
+            let fs = SAMPLING_RATE as f64;
+            let oldest = ((now - start) as f64 / fs - view.window).max(0.0);
+            let mut back = (now - end) as f64 / fs;
+            let slider = Slider::new(&mut back, oldest..=0.0).suffix(" s").text("before now");
+            if ui.add(slider).changed() {
+                view.scroll((now - end) as f64 / fs - back);
+            }
+            if ui.button("⏩ Live").clicked() {
+                view.follow();
+            }
+        } else {
+            ui.label(RichText::new("(drag the plots to scroll back)").italics());
+        }
+
+        ui.separator();
+        let num_marks = HISTORY.read().unwrap().marks().len();
+        let marks_button = Button::new(format!("Marks ({})", num_marks)).selected(view.show_marks);
+        if ui.add(marks_button).clicked() {
+            view.show_marks = !view.show_marks;
+        }
+        if ui.add(Button::new("Trends").selected(view.show_trends)).clicked() {
+            view.show_trends = !view.show_trends;
+        }
+    });
+    marks_window(ui.ctx(), view);
+    trends_window(ui.ctx(), view);
+    if view.layout == Layout::Rows {
+        ui.horizontal(|ui| {
+            let spectrogram_button = Button::new("Spectrogram").selected(view.show_spectrogram);
+            if ui.add(spectrogram_button).clicked() {
+                view.show_spectrogram = !view.show_spectrogram;
+            }
+            if view.show_spectrogram {
+                view.spectrogram.controls(ui);
+            } else {
+                psd_controls(ui, view);
+            }
+        });
+    }
+}
+
+/// Display of the PSD and settings of Welch's method.
+fn psd_controls(ui: &mut egui::Ui, view: &mut LiveView) {
+    ui.label("Spectrum: ");
+    ui.selectable_value(&mut view.psd_line, false, "Bars");
+    ui.selectable_value(&mut view.psd_line, true, "Line");
+    ui.checkbox(&mut view.psd_db, "dB");
+
+    ui.separator();
+    let mut opts = *PSD_OPTIONS.read().unwrap();
+    let fs = SAMPLING_RATE as f64;
+    ui.label("Segment: ");
+    egui::ComboBox::from_id_source("psd segment")
+        .selected_text(format!("{} ({:.2} s)", opts.segment_len, opts.segment_len as f64 / fs))
+        .show_ui(ui, |ui| {
+            for len in psd::SEGMENT_LENGTHS {
+                let text = format!("{} ({:.2} s, {:.2} Hz)", len, len as f64 / fs, fs / len as f64);
+                ui.selectable_value(&mut opts.segment_len, len, text);
+            }
+        });
+    ui.label("Overlap: ");
+    let mut overlap = opts.overlap * 100.0;
+    ui.add(Slider::new(&mut overlap, 0.0..=psd::MAX_OVERLAP * 100.0).suffix(" %"));
+    opts.overlap = overlap / 100.0;
+    ui.label("Averaging: ");
+    ui.radio_value(&mut opts.averaging, Averaging::Mean, "mean");
+    ui.radio_value(&mut opts.averaging, Averaging::Median, "median");
+    let segments = (WAVE_BUFF_LEN - opts.segment_len) / ((opts.segment_len as f64 * (1.0 - opts.overlap)).round() as usize).max(1) + 1;
+    ui.label(format!("({} segments)", segments));
+    *PSD_OPTIONS.write().unwrap() = opts;
+}
+
+/// Window with the marks received during the last `HISTORY_SECS`, newest
+/// first, to check that the triggers arrive.
+fn marks_window(ctx: &egui::Context, view: &mut LiveView) {
+    let mut open = view.show_marks;
+    egui::Window::new("Recent marks").open(&mut open).show(ctx, |ui| {
+        let history = HISTORY.read().unwrap();
+        if history.marks().len() == 0 {
+            ui.label("No marks received yet");
+            return;
+        }
+        egui::ScrollArea::vertical().max_height(300.0).show(ui, |ui| {
+            egui::Grid::new("recent marks").striped(true).show(ui, |ui| {
+                for title in ["Time", "Mark", "Ago"] {
+                    ui.label(RichText::new(title).strong());
+                }
+                ui.end_row();
+                for mark in history.marks().rev() {
+                    let ago = (history.end() - mark.sample) as f64 / SAMPLING_RATE as f64;
+                    ui.label(mark.time.format("%H:%M:%S%.3f").to_string());
+                    ui.label(view.mark_text(mark.code));
+                    ui.label(format!("{:.1} s", ago));
+                    ui.end_row();
+                }
+            });
+        });
+    });
+    view.show_marks = open;
+}
+
+/// Window with the band power trends of every channel. Follows the freeze
+/// of the live plots.
+fn trends_window(ctx: &egui::Context, view: &mut LiveView) {
+    let mut open = view.show_trends;
+    let end = view.end.unwrap_or_else(|| HISTORY.read().unwrap().end());
+    egui::Window::new("Band power trends")
+        .open(&mut open)
+        .default_size([500.0, 700.0])
+        .show(ctx, |ui| {
+            view.trends.controls(ui);
+            ui.separator();
+            egui::ScrollArea::vertical().show(ui, |ui| view.trends.plot(ui, end));
+        });
+    view.show_trends = open;
+}
+
+/// Draws the marks of the samples `start..end` of `HISTORY` as vertical
+/// lines, with their code and label at height `top` if given.
+fn draw_marks(plot_ui: &mut PlotUi, view: &LiveView, start: usize, end: usize, top: Option<f64>) {
+    let fs = SAMPLING_RATE as f64;
+    let history = HISTORY.read().unwrap();
+    for mark in history.marks().filter(|m| (start..end).contains(&m.sample)) {
+        let x = mark.sample as f64 / fs;
+        plot_ui.vline(VLine::new(x).color(MARK_COLOR).width(1.5));
+        if let Some(top) = top {
+            let text = Text::new(PlotPoint::new(x, top), view.mark_text(mark.code))
+                .anchor(egui::Align2::LEFT_TOP)
+                .color(MARK_COLOR);
+            plot_ui.text(text);
+        }
+    }
+}
+
+/// Points of the samples `start..end` of wave `ch` in `HISTORY`, with the
+/// time in seconds and the value in µV. Groups of samples are replaced by their minimum and
+/// maximum when there are more than `MAX_POINTS`.
+fn history_points(ch: usize, start: usize, end: usize) -> Vec<[f64; 2]> {
+    let history = HISTORY.read().unwrap();
+    let fs = SAMPLING_RATE as f64;
+    let first = start.max(history.start());
+    let samples: Vec<f32> = history.range(ch, start, end).collect();
+    let group = samples.len().div_ceil(MAX_POINTS / 2).max(1);
+    if group == 1 {
+        return samples
+            .iter()
+            .enumerate()
+            .map(|(i, v)| [(first + i) as f64 / fs, *v as f64 * FULL_SCALE_UV])
+            .collect();
+    }
+    let mut points = Vec::with_capacity(MAX_POINTS + 2);
+    for (g, chunk) in samples.chunks(group).enumerate() {
+        let t = (first + g * group) as f64 / fs;
+        let (min, max) = chunk.iter().fold((f32::MAX, f32::MIN), |(min, max), v| (min.min(*v), max.max(*v)));
+        points.push([t, min as f64 * FULL_SCALE_UV]);
+        points.push([t, max as f64 * FULL_SCALE_UV]);
+    }
+    points
+}
+
+/// Wave of a channel ready to draw in a raw plot.
+struct Trace {
+    /// Time (s) and value (µV) of the points, centered on the mean of the
+    /// window with a fixed sensitivity
+    points: Vec<[f64; 2]>,
+    /// Points out of the plot with the fixed sensitivity, moved to its edge
+    clipped: Vec<[f64; 2]>,
+    /// Whether the wave reaches the input range of the ADC
+    saturated: bool,
+}
+
+impl Trace {
+    fn new(view: &LiveView, ch: usize, start: usize, end: usize) -> Self {
+        let mut points = history_points(ch, start, end);
+        let saturated = points.iter().any(|p| p[1].abs() >= ADC_SATURATION * FULL_SCALE_UV);
+        let half_range = view.channel_sensitivity(ch) * DIVISIONS / 2.0;
+        let mut clipped = vec![];
+        if !view.autoscale {
+            let mean = points.iter().map(|p| p[1]).sum::<f64>() / points.len().max(1) as f64;
+            for p in points.iter_mut() {
+                p[1] -= mean;
+                if p[1].abs() > half_range {
+                    p[1] = p[1].clamp(-half_range, half_range);
+                    clipped.push(*p);
+                }
+            }
+        }
+        Trace { points, clipped, saturated }
+    }
+}
+
+/// Samples of `HISTORY` shown in the raw plots.
+fn shown_range(view: &LiveView) -> (usize, usize) {
+    let end = view.end.unwrap_or_else(|| HISTORY.read().unwrap().end());
+    let window = (view.window * SAMPLING_RATE as f64).round() as usize;
+    (end.saturating_sub(window), end)
+}
+
+/// Seconds a raw plot was dragged by, negative to go back.
+fn drag_seconds(response: &egui::Response, view: &LiveView) -> f64 {
+    if response.dragged() {
+        -response.drag_delta().x as f64 * view.window / response.rect.width() as f64
+    } else {
+        0.0
+    }
+}
+
+/// This function draws the wave and FFT plots. The raw plots show the
+/// window of `view` from `HISTORY` and the FFTs are read from `FFT_BUFFS`,
+/// or drawn over time from `SPECTROGRAM` if `view.show_spectrogram`.
+/// Dragging a raw plot scrolls back in the history.
+///
+/// With a fixed sensitivity the raw plots are centered on the mean of the
+/// window, and the parts of the wave out of the plot are drawn at its edge
+/// in red.
+pub fn plot_waves(ui: &mut egui::Ui, view: &mut LiveView) {
+    if view.layout == Layout::Stacked {
+        plot_stacked(ui, view);
+        return;
+    }
 
-/// This function draws the wave and FFT plots. The data used to
-/// generate the plots is read from the global variables: `FFT_BUFFS`
-/// and `WAVE_BUFFS`.
-pub fn plot_waves(ui: &mut egui::Ui) {
     let space = Vec2::from(&[
         ui.available_width(),
         (ui.available_height() / WAVE_BUFFS_NUM as f32) - 15.,
     ]);
 
     // read the data from the global buffers
-    let fft_buffs = FFT_BUFFS.read().unwrap();
-    let wave_buffs = WAVE_BUFFS.read().unwrap();
-
-    let colors = vec![
-        Color32::from_rgb(255, 59, 71),   // tomato
-        Color32::from_rgb(135, 206, 235), // sky blue
-        Color32::from_rgb(0, 255, 127),   // SpringGreen
-        Color32::from_rgb(106, 90, 205),  // SlateBlue
-        Color32::from_rgb(255, 255, 153),  // Dav-id Yellow
-        Color32::from_rgb(255, 139, 255),  // NIT Pink
-    ];
+    let psd = match &view.frozen_psd {
+        Some(psd) => psd.clone(),
+        None => PSD.read().unwrap().clone(),
+    };
+    let (start, end) = shown_range(view);
+    // seconds the raw plots were dragged by
+    let mut dragged = 0.0;
+
+    let colors = CHANNEL_COLORS;
     let mut color_idx = 0;
-    let mut idx = 0;
-    for (fft_buff, wave_buff) in fft_buffs.iter().zip(wave_buffs.iter()) {
+    for (idx, psd_values) in psd.channels.iter().enumerate() {
         ui.allocate_ui(space, |ui| {
             ui.columns(2, |columns| {
-                // convert numeric data (`f32`) to egui's `Value` struct in order to
-                // generate the plots
-                let raw_line = Line::new(PlotPoints::from_ys_f32(
-                    // reverse buffer
-                    wave_buff
-                        .iter()
-                        .map(|v| *v)
-                        .collect::<Vec<f32>>()
-                        .as_slice(),
-                ))
-                .color(colors[color_idx]);
+                let Trace { points, clipped, saturated } = Trace::new(view, idx, start, end);
+                let div = view.channel_sensitivity(idx);
+                let half_range = div * DIVISIONS / 2.0;
+                let raw_line = Line::new(PlotPoints::new(points)).color(colors[color_idx]);
+                let clip_points = Points::new(clipped.clone()).color(Color32::RED).radius(1.5);
 
-                let num_bins = WAVE_BUFF_LEN as f64 / 2.0;
-                let nyquist = SAMPLING_RATE as f64 / 2.0;
-                let bin_size = nyquist / num_bins;
-                let fft_bars: Vec<Bar> = fft_buff
+                let bin_size = psd.bin_size;
+                let psd_bars: Vec<Bar> = psd_values
                     .iter()
                     .enumerate()
                     .map(|(i, v)|{
                         let freq = (i as f64) * bin_size;
-                        let mag = *v as f64;
-                        Bar::new(freq, mag)
-                            .width(0.095)
-                            .name(format!("{freq} Hz"))
+                        Bar::new(freq, *v as f64)
+                            .width(0.8 * bin_size)
+                            .name(format!("{freq:.2} Hz"))
                          })
-                    .take_while(|v| v.argument <= 60.0)
+                    .take_while(|v| v.argument <= bands::MAX_FREQ)
                     .collect();
 
-                // Calculate the frequency bands
-                let alpha_mag = frequency_band(&fft_bars, 8.0, 12.0);
-                let theta_mag = frequency_band(&fft_bars, 5.0, 7.0);
-                let beta_mag = frequency_band(&fft_bars, 13.0, 20.0);
+                // Calculate the frequency bands, as a share of the power
+                let bands = BANDS.read().unwrap().clone();
+                let band_mags: Vec<f64> = bands.iter().map(|b| b.share(psd_values, bin_size)).collect();
 
-                let fft_barchart = BarChart::new(fft_bars).color(colors[color_idx]);
+                let psd_points: Vec<[f64; 2]> = psd_bars
+                    .iter()
+                    .map(|b| [b.argument, if view.psd_db { psd::to_db(b.value as f32) } else { b.value }])
+                    .collect();
+                // bars in dB grow from the lowest value
+                let floor = if view.psd_db {
+                    (psd_points.iter().map(|p| p[1]).fold(f64::MAX, f64::min) / 10.0).floor() * 10.0
+                } else {
+                    0.0
+                };
+                let psd_barchart = BarChart::new(
+                    psd_bars
+                        .into_iter()
+                        .zip(&psd_points)
+                        .map(|(bar, p)| Bar { value: p[1] - floor, ..bar }.base_offset(floor))
+                        .collect(),
+                )
+                .color(colors[color_idx]);
+                let psd_line = Line::new(PlotPoints::new(psd_points)).color(colors[color_idx]);
 
-                let band_bars = [("theta", theta_mag), ("alpha", alpha_mag), ("beta", beta_mag)].iter()
+                let band_bars = bands.iter().zip(&band_mags)
                     .enumerate()
-                    .map(|(i, (name, v))| Bar::new(i as f64, *v).name(name))
+                    .map(|(i, (band, v))| Bar::new(i as f64, *v).name(&band.name).fill(band.color))
                     .collect::<Vec<Bar>>();
                 let bands_barchart = BarChart::new(band_bars)
-                    .color(colors[color_idx])
                     .width(1.0);
 
                 if color_idx == colors.len() - 1 {
@@ -80,7 +493,7 @@ pub fn plot_waves(ui: &mut egui::Ui) {
                     color_idx += 1;
                 }
 
-                columns[0].horizontal_top(|mut ui| {
+                columns[0].horizontal_top(|ui| {
                     ui.vertical(|ui| {
                         ui.horizontal(|ui| {
                             let ch_ok = CH_STATUS.read().unwrap()[idx];
@@ -94,34 +507,90 @@ pub fn plot_waves(ui: &mut egui::Ui) {
                             // Channel label
                             let text = RichText::new(format!("CH-{}", idx+1)).strong();
                             ui.label(text);
-                        });
 
-                        ui.label(format!("theta: {:.2}%", theta_mag));
-                        ui.label(format!("alpha: {:.2}%", alpha_mag));
-                        ui.label(format!("beta: {:.2}%", beta_mag));
+                            if saturated {
+                                ui.label(RichText::new("CLIP").color(Color32::RED).strong())
+                                    .on_hover_text("The signal reaches the input range of the ADC");
+                            } else if !clipped.is_empty() {
+                                ui.label(RichText::new("CLIP").color(Color32::YELLOW).strong())
+                                    .on_hover_text("The signal exceeds the plot, lower the sensitivity or the gain");
+                            }
+                        });
+                        ui.add_enabled(
+                            !view.autoscale,
+                            DragValue::new(&mut view.channel_gains[idx])
+                                .clamp_range(MIN_GAIN..=MAX_GAIN)
+                                .speed(0.05)
+                                .prefix("gain ×"),
+                        );
 
+                        band_labels(ui, ("live bands", idx), &bands, &band_mags);
                     });
 
                     let legend = Legend::default();
 
-                    Plot::new(format!("Raw wave {idx}"))
+                    let fs = SAMPLING_RATE as f64;
+                    let (x_min, x_max) = (start as f64 / fs, end as f64 / fs);
+                    let mut plot = Plot::new(format!("Raw wave {idx}"))
                         .allow_drag(false)
                         .allow_zoom(false)
+                        .allow_scroll(false)
+                        .include_x(x_min)
+                        .include_x(x_max)
                         // .include_y(1)
                         // .center_y_axis(true)
                         .legend(legend)
-                        .show_axes([false, false])
-                        .show(&mut ui, |plot_ui| plot_ui.line(raw_line));
+                        .show_axes([false, true])
+                        .y_axis_formatter(|mark, _, _| {
+                            let decimals = if mark.step_size < 1.0 { 1 } else { 0 };
+                            format!("{:.*} µV", decimals, mark.value)
+                        });
+                    if !view.autoscale {
+                        plot = plot.y_grid_spacer(uniform_grid_spacer(move |_| [div / 5.0, div, div * DIVISIONS / 2.0]));
+                    }
+                    let response = plot
+                        .show(ui, |plot_ui| {
+                            let top = if view.autoscale {
+                                plot_ui.plot_bounds().max()[1]
+                            } else {
+                                plot_ui.set_plot_bounds(PlotBounds::from_min_max(
+                                    [x_min, -half_range],
+                                    [x_max, half_range],
+                                ));
+                                half_range
+                            };
+                            plot_ui.line(raw_line);
+                            plot_ui.points(clip_points);
+                            // the labels of the marks only on the first channel
+                            draw_marks(plot_ui, view, start, end, Some(top).filter(|_| idx == 0));
+                        })
+                        .response;
+                    if response.dragged() {
+                        dragged = drag_seconds(&response, view);
+                    }
                 });
 
-                columns[1].horizontal_top(|mut ui| {
+                columns[1].horizontal_top(|ui| {
                     let max_width = ui.max_rect().width() - 10.0;
-                    Plot::new(format!("FFT {idx}"))
-                        .allow_drag(false)
-                        .allow_zoom(false)
-                        .show_y(false)
-                        .width(0.7 * max_width)
-                        .show(&mut ui, |plot_ui| plot_ui.bar_chart(fft_barchart));
+                    if view.show_spectrogram {
+                        view.spectrogram.plot(ui, idx, end, 0.7 * max_width);
+                    } else {
+                        let unit = if view.psd_db { "dB" } else { "µV²/Hz" };
+                        let psd_line_shown = view.psd_line;
+                        Plot::new(format!("PSD {idx}"))
+                            .allow_drag(false)
+                            .allow_zoom(false)
+                            .show_y(false)
+                            .y_axis_formatter(move |mark, _, _| format!("{} {}", mark.value, unit))
+                            .width(0.7 * max_width)
+                            .show(ui, |plot_ui| {
+                                if psd_line_shown {
+                                    plot_ui.line(psd_line);
+                                } else {
+                                    plot_ui.bar_chart(psd_barchart);
+                                }
+                            });
+                    }
 
                     Plot::new(format!("Frequency bands {idx}"))
                         .allow_drag(false)
@@ -131,11 +600,11 @@ pub fn plot_waves(ui: &mut egui::Ui) {
                         .include_y(20.0)
                         .show_axes([false, false])
                         .width(0.3 * max_width)
-                        .show(&mut ui, |plot_ui|
+                        .show(ui, |plot_ui|
                               {
                                   plot_ui.bar_chart(bands_barchart);
-                                  for (i, &label) in ["theta", "alpha", "beta"].iter().enumerate() {
-                                      let text = Text::new(PlotPoint::new(i as f64, 0.), label)
+                                  for (i, band) in bands.iter().enumerate() {
+                                      let text = Text::new(PlotPoint::new(i as f64, 0.), &band.name)
                                           .color(Color32::WHITE);
                                       plot_ui.text(text);
                                   }
@@ -143,18 +612,106 @@ pub fn plot_waves(ui: &mut egui::Ui) {
                 });
             });
         });
-        idx += 1;
     }
+
+    if dragged != 0.0 {
+        view.scroll(dragged);
+    }
+}
+
+/// Percentage of each band in `mags`, in two columns with the colors of the
+/// bands.
+pub fn band_labels(ui: &mut egui::Ui, id: impl std::hash::Hash, bands: &[bands::Band], mags: &[f64]) {
+    egui::Grid::new(id).num_columns(2).spacing([8.0, 0.0]).show(ui, |ui| {
+        for (i, (band, mag)) in bands.iter().zip(mags).enumerate() {
+            ui.label(RichText::new(format!("{}: {:.2}%", band.name, mag)).color(band.color));
+            if i % 2 == 1 {
+                ui.end_row();
+            }
+        }
+    });
 }
 
-/// Compute the magnitude of a frequency band (bounds included) normalized (percentage) w.r.t the sum of the magnitudes of the FFT.
-fn frequency_band(fft: &Vec<Bar>, start: f64, end: f64) -> f64 {
-    let fft_mag_sum = fft.iter().map(|v| v.value).sum::<f64>();
-    let band_sum =
-        fft.iter()
-        .filter(|v| v.argument >= start && v.argument <= end)
-        .map(|v| v.value)
-        .sum::<f64>();
-    let v = 100.0 * band_sum / fft_mag_sum;
-    if v.is_nan() { 0.0 } else { v }
+/// Draws all channels in a single plot, one row per channel from the top,
+/// labeled with the channel and its lead-off status on the left. Each row is
+/// `DIVISIONS` of the channel sensitivity high, or fits the wave in the
+/// window with autoscale.
+fn plot_stacked(ui: &mut egui::Ui, view: &mut LiveView) {
+    let (start, end) = shown_range(view);
+    let fs = SAMPLING_RATE as f64;
+    let (x_min, x_max) = (start as f64 / fs, end as f64 / fs);
+    let num_ch = WAVE_BUFFS_NUM as f64;
+    let status = *CH_STATUS.read().unwrap();
+
+    let mut lines = vec![];
+    let mut clips = vec![];
+    let mut labels = vec![];
+    for ch in 0..WAVE_BUFFS_NUM {
+        let Trace { mut points, mut clipped, saturated } = Trace::new(view, ch, start, end);
+        let row = num_ch - 1.0 - ch as f64;
+        let scale = if view.autoscale {
+            let mean = points.iter().map(|p| p[1]).sum::<f64>() / points.len().max(1) as f64;
+            let peak = points.iter().map(|p| (p[1] - mean).abs()).fold(0.0, f64::max);
+            for p in points.iter_mut() {
+                p[1] -= mean;
+            }
+            if peak > 0.0 { 0.45 / peak } else { 1.0 }
+        } else {
+            1.0 / (view.channel_sensitivity(ch) * DIVISIONS)
+        };
+        for p in points.iter_mut().chain(clipped.iter_mut()) {
+            p[1] = row + p[1] * scale;
+        }
+
+        let color = CHANNEL_COLORS[ch % CHANNEL_COLORS.len()];
+        lines.push(Line::new(PlotPoints::new(points)).color(color));
+        clips.push(Points::new(clipped.clone()).color(Color32::RED).radius(1.5));
+        let mut label = format!("CH-{}", ch + 1);
+        if !status[ch] {
+            label.push_str(" off");
+        }
+        if saturated || !clipped.is_empty() {
+            label.push_str(" CLIP");
+        }
+        labels.push(label);
+    }
+
+    let scale_text = if view.autoscale {
+        "autoscale".to_string()
+    } else {
+        format!("{} µV/div", view.sensitivity / view.gain)
+    };
+    let response = Plot::new("Stacked waves")
+        .height(ui.available_height() - 40.0)
+        .allow_drag(false)
+        .allow_zoom(false)
+        .allow_scroll(false)
+        .allow_double_click_reset(false)
+        .show_axes([true, true])
+        .y_axis_width(8)
+        .y_grid_spacer(|_| (0..WAVE_BUFFS_NUM).map(|row| GridMark { value: row as f64, step_size: 1.0 }).collect())
+        .y_axis_formatter(move |mark, _, _| {
+            let ch = num_ch - 1.0 - mark.value.round();
+            if (mark.value - mark.value.round()).abs() < 1e-6 && (0.0..num_ch).contains(&ch) {
+                labels[ch as usize].clone()
+            } else {
+                String::new()
+            }
+        })
+        .x_axis_formatter(|mark, _, _| format!("{:.1} s", mark.value))
+        .show(ui, |plot_ui| {
+            plot_ui.set_plot_bounds(PlotBounds::from_min_max([x_min, -0.5], [x_max, num_ch - 0.5]));
+            draw_marks(plot_ui, view, start, end, Some(num_ch - 0.5));
+            for (line, clip) in lines.into_iter().zip(clips) {
+                plot_ui.line(line);
+                plot_ui.points(clip);
+            }
+            let corner = PlotPoint::new(x_max, num_ch - 0.5);
+            plot_ui.text(Text::new(corner, scale_text).anchor(egui::Align2::RIGHT_TOP).color(Color32::GRAY));
+        })
+        .response;
+    let dragged = drag_seconds(&response, view);
+    if dragged != 0.0 {
+        view.scroll(dragged);
+    }
 }
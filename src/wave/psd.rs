@@ -0,0 +1,139 @@
+//! Power spectral density of the waves by Welch's method: the buffer is split
+//! into overlapping segments, each one detrended, Hann windowed and
+//! transformed, and their periodograms averaged.
+
+use std::f32::consts::PI;
+
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+
+use super::*;
+
+/// Segment lengths offered, in samples.
+pub const SEGMENT_LENGTHS: [usize; 5] = [64, 128, 256, 512, 1024];
+/// Largest overlap between segments, as a fraction of their length.
+pub const MAX_OVERLAP: f64 = 0.9;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Averaging {
+    Mean,
+    /// Median of the periodograms, robust to short artifacts
+    Median,
+}
+
+#[derive(Clone, Copy)]
+pub struct PsdOptions {
+    /// Samples per segment, one of `SEGMENT_LENGTHS`
+    pub segment_len: usize,
+    /// Overlap between consecutive segments, as a fraction of their length
+    pub overlap: f64,
+    pub averaging: Averaging,
+}
+
+impl Default for PsdOptions {
+    fn default() -> Self {
+        PsdOptions { segment_len: 256, overlap: 0.5, averaging: Averaging::Mean }
+    }
+}
+
+/// One-sided PSD of each wave, in µV²/Hz.
+#[derive(Clone, Default)]
+pub struct Psd {
+    /// Frequency step between the values, in Hz
+    pub bin_size: f64,
+    /// Values of each wave from 0 Hz to the Nyquist frequency
+    pub channels: Vec<Vec<f32>>,
+}
+
+impl Psd {
+    /// PSD of the wave buffers.
+    pub fn compute(waves: &[[f32; WAVE_BUFF_LEN]; WAVE_BUFFS_NUM], opts: &PsdOptions) -> Self {
+        let waves: Vec<Vec<f32>> = waves
+            .iter()
+            .map(|wave| wave.iter().map(|x| x * FULL_SCALE_UV as f32).collect())
+            .collect();
+        Psd::welch(&waves, SAMPLING_RATE as f64, opts)
+    }
+
+    /// PSD of waves in µV sampled at `fs` Hz. Segments longer than the waves
+    /// are shortened to their length.
+    pub fn welch(waves: &[Vec<f32>], fs: f64, opts: &PsdOptions) -> Self {
+        let len = opts.segment_len.min(waves.iter().map(Vec::len).min().unwrap_or(0));
+        if len < 2 {
+            return Psd { bin_size: fs, channels: vec![vec![]; waves.len()] };
+        }
+        let fft = FftPlanner::new().plan_fft_forward(len);
+        let window: Vec<f32> = (0..len).map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / len as f32).cos()).collect();
+        let fs = fs as f32;
+        // density scaling, as `scipy.signal.welch`
+        let scale = 1.0 / (fs * window.iter().map(|w| w * w).sum::<f32>());
+        let step = ((len as f64 * (1.0 - opts.overlap.clamp(0.0, MAX_OVERLAP))).round() as usize).max(1);
+        let num_bins = len / 2 + 1;
+
+        let channels = waves
+            .iter()
+            .map(|wave| {
+                let mut periodograms = vec![];
+                let mut start = 0;
+                while start + len <= wave.len() {
+                    let segment = &wave[start..start + len];
+                    let mean = segment.iter().sum::<f32>() / len as f32;
+                    let mut buffer: Vec<Complex<f32>> = segment
+                        .iter()
+                        .zip(&window)
+                        .map(|(x, w)| Complex { re: (x - mean) * w, im: 0.0 })
+                        .collect();
+                    fft.process(&mut buffer);
+                    let periodogram: Vec<f32> = (0..num_bins)
+                        .map(|k| {
+                            // one-sided: the power of the negative frequencies is added,
+                            // except for DC and Nyquist, which have none
+                            let one_sided = if k == 0 || 2 * k == len { 1.0 } else { 2.0 };
+                            buffer[k].norm_sqr() * scale * one_sided
+                        })
+                        .collect();
+                    periodograms.push(periodogram);
+                    start += step;
+                }
+                average(&periodograms, num_bins, opts.averaging)
+            })
+            .collect();
+        Psd { bin_size: (fs / len as f32) as f64, channels }
+    }
+}
+
+fn average(periodograms: &[Vec<f32>], num_bins: usize, averaging: Averaging) -> Vec<f32> {
+    let n = periodograms.len();
+    match averaging {
+        Averaging::Mean => (0..num_bins)
+            .map(|k| periodograms.iter().map(|p| p[k]).sum::<f32>() / n as f32)
+            .collect(),
+        Averaging::Median => {
+            let bias = median_bias(n);
+            (0..num_bins)
+                .map(|k| {
+                    let mut values: Vec<f32> = periodograms.iter().map(|p| p[k]).collect();
+                    values.sort_by(f32::total_cmp);
+                    let median = if n % 2 == 1 {
+                        values[n / 2]
+                    } else {
+                        (values[n / 2 - 1] + values[n / 2]) / 2.0
+                    };
+                    median / bias
+                })
+                .collect()
+        }
+    }
+}
+
+/// Ratio of the median to the mean of `n` periodograms, to make the median
+/// an unbiased estimate, as in `scipy.signal.welch`.
+fn median_bias(n: usize) -> f32 {
+    let ii_2 = (1..=(n.saturating_sub(1) / 2)).map(|k| 2.0 * k as f32);
+    1.0 + ii_2.map(|ii| 1.0 / (ii + 1.0) - 1.0 / ii).sum::<f32>()
+}
+
+/// Decibels of a PSD value, relative to 1 µV²/Hz.
+pub fn to_db(v: f32) -> f64 {
+    10.0 * (v.max(1e-12) as f64).log10()
+}
@@ -1,7 +1,8 @@
 use super::*;
 use rustfft::num_complex::Complex;
 
-/// Generates the FFTs of the wave buffers.
+/// Generates the FFTs and the Welch PSD of the wave buffers, and appends the
+/// PSD to the spectrograms and the band power trends.
 pub fn fft_gen() {
     let mut fft_buffs = FFT_BUFFS.write().unwrap();
     let wave_buffs = WAVE_BUFFS.read().unwrap();
@@ -17,4 +18,12 @@ pub fn fft_gen() {
             fft_buff[i] = d.norm() * *FFT_SCALE;
         }
     }
+
+    let opts = *PSD_OPTIONS.read().unwrap();
+    let psd = psd::Psd::compute(&wave_buffs, &opts);
+
+    let end = HISTORY.read().unwrap().end();
+    SPECTROGRAM.write().unwrap().push(end, &psd);
+    TRENDS.write().unwrap().push(end, &psd);
+    *PSD.write().unwrap() = psd;
 }
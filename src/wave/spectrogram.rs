@@ -0,0 +1,207 @@
+//! Scrolling time-frequency view of the channels. The Welch PSDs computed by
+//! `read::fft_gen` are kept in `SPECTROGRAM` and drawn as an image, with the
+//! time in the horizontal axis and the frequency in the vertical one.
+
+use std::collections::VecDeque;
+
+use eframe::egui::{self, Color32, ColorImage, DragValue, TextureHandle, TextureOptions};
+use egui_plot::{Plot, PlotBounds, PlotImage, PlotPoint};
+
+use super::*;
+
+/// Seconds of spectra kept for the spectrograms.
+pub const SPECTROGRAM_SECS: f64 = 60.0;
+
+/// PSDs of the channels over time.
+pub struct Spectrogram {
+    /// PSDs as in `PSD`, oldest first, all with the same bin size
+    columns: VecDeque<psd::Psd>,
+    /// Index in `HISTORY` of the end of the window of each spectrum
+    ends: VecDeque<usize>,
+}
+
+impl Spectrogram {
+    pub(super) fn new() -> Self {
+        Spectrogram { columns: VecDeque::new(), ends: VecDeque::new() }
+    }
+
+    /// Appends the PSDs of the window ending at sample `end` of `HISTORY`,
+    /// unless no sample arrived since the last ones. Changing the segment
+    /// length of the PSD starts the spectrogram over.
+    pub fn push(&mut self, end: usize, psd: &psd::Psd) {
+        if self.ends.back() == Some(&end) {
+            return;
+        }
+        if self.columns.back().is_some_and(|c| c.bin_size != psd.bin_size) {
+            self.columns.clear();
+            self.ends.clear();
+        }
+        self.columns.push_back(psd.clone());
+        self.ends.push_back(end);
+        let oldest = end.saturating_sub((SPECTROGRAM_SECS * SAMPLING_RATE as f64) as usize);
+        while self.ends.front().is_some_and(|e| *e < oldest) {
+            self.ends.pop_front();
+            self.columns.pop_front();
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Colormap {
+    Viridis,
+    Inferno,
+    Grayscale,
+}
+
+impl Colormap {
+    const ALL: [Colormap; 3] = [Colormap::Viridis, Colormap::Inferno, Colormap::Grayscale];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Colormap::Viridis => "Viridis",
+            Colormap::Inferno => "Inferno",
+            Colormap::Grayscale => "Grayscale",
+        }
+    }
+
+    /// Color of `v`, from 0 to 1.
+    fn color(&self, v: f32) -> Color32 {
+        let stops: &[[u8; 3]] = match self {
+            Colormap::Viridis => &[[68, 1, 84], [59, 82, 139], [33, 145, 140], [94, 201, 98], [253, 231, 37]],
+            Colormap::Inferno => &[[0, 0, 4], [87, 16, 110], [188, 55, 84], [249, 142, 9], [252, 255, 164]],
+            Colormap::Grayscale => &[[0, 0, 0], [255, 255, 255]],
+        };
+        let x = v.clamp(0.0, 1.0) * (stops.len() - 1) as f32;
+        let i = (x.floor() as usize).min(stops.len() - 2);
+        let frac = x - i as f32;
+        let mix = |c: usize| (stops[i][c] as f32 + (stops[i + 1][c] as f32 - stops[i][c] as f32) * frac) as u8;
+        Color32::from_rgb(mix(0), mix(1), mix(2))
+    }
+}
+
+/// Settings of the spectrograms and their textures.
+pub struct SpectrogramView {
+    /// Frequency range shown, in Hz
+    pub min_freq: f64,
+    pub max_freq: f64,
+    /// PSDs (dB re 1 µV²/Hz) mapped to the ends of the colormap
+    pub min_psd_db: f64,
+    pub max_psd_db: f64,
+    pub colormap: Colormap,
+    textures: Vec<Option<TextureHandle>>,
+}
+
+impl Default for SpectrogramView {
+    fn default() -> Self {
+        SpectrogramView {
+            min_freq: 0.0,
+            max_freq: 40.0,
+            min_psd_db: -20.0,
+            max_psd_db: 30.0,
+            colormap: Colormap::Viridis,
+            textures: vec![None; WAVE_BUFFS_NUM],
+        }
+    }
+}
+
+impl SpectrogramView {
+    pub fn controls(&mut self, ui: &mut egui::Ui) {
+        let nyquist = SAMPLING_RATE as f64 / 2.0;
+        ui.label("Frequencies: ");
+        ui.add(DragValue::new(&mut self.min_freq).clamp_range(0.0..=nyquist).speed(0.5).suffix(" Hz"));
+        ui.label("to");
+        ui.add(DragValue::new(&mut self.max_freq).clamp_range(0.0..=nyquist).speed(0.5).suffix(" Hz"));
+        ui.label("Range: ");
+        ui.add(DragValue::new(&mut self.min_psd_db).clamp_range(-60.0..=120.0).speed(0.5).suffix(" dB"));
+        ui.label("to");
+        ui.add(DragValue::new(&mut self.max_psd_db).clamp_range(-60.0..=120.0).speed(0.5).suffix(" dB"))
+            .on_hover_text("PSD in dB re 1 µV²/Hz");
+        egui::ComboBox::from_id_source("spectrogram colormap")
+            .selected_text(self.colormap.name())
+            .show_ui(ui, |ui| {
+                for colormap in Colormap::ALL {
+                    ui.selectable_value(&mut self.colormap, colormap, colormap.name());
+                }
+            });
+        if self.max_freq <= self.min_freq {
+            self.max_freq = (self.min_freq + 1.0).min(nyquist);
+            self.min_freq = self.max_freq - 1.0;
+        }
+        if self.max_psd_db <= self.min_psd_db {
+            self.max_psd_db = self.min_psd_db + 1.0;
+        }
+    }
+
+    /// Image of the PSDs of channel `ch` with windows ending up to sample
+    /// `end`, one column per PSD. `None` if there are none.
+    fn image(&self, ch: usize, end: usize) -> Option<(ColorImage, usize, usize)> {
+        let spectrogram = SPECTROGRAM.read().unwrap();
+        let oldest = end.saturating_sub((SPECTROGRAM_SECS * SAMPLING_RATE as f64) as usize);
+        let shown: Vec<usize> = (0..spectrogram.ends.len())
+            .filter(|i| (oldest..=end).contains(&spectrogram.ends[*i]))
+            .collect();
+        let (first, last) = (*shown.first()?, *shown.last()?);
+
+        let bin_size = spectrogram.columns[last].bin_size;
+        let bins: Vec<usize> = (0..spectrogram.columns[last].channels[ch].len())
+            .filter(|i| (self.min_freq..=self.max_freq).contains(&(*i as f64 * bin_size)))
+            .rev()
+            .collect();
+        if bins.is_empty() {
+            return None;
+        }
+
+        let mut image = ColorImage::new([shown.len(), bins.len()], Color32::BLACK);
+        let db_range = (self.max_psd_db - self.min_psd_db) as f32;
+        for (y, bin) in bins.iter().enumerate() {
+            for (x, i) in shown.iter().enumerate() {
+                let db = psd::to_db(spectrogram.columns[*i].channels[ch][*bin]);
+                image[(x, y)] = self.colormap.color((db - self.min_psd_db) as f32 / db_range);
+            }
+        }
+        Some((image, spectrogram.ends[first], spectrogram.ends[last]))
+    }
+
+    /// Draws the spectrogram of channel `ch` for the `SPECTROGRAM_SECS` up to
+    /// sample `end` of `HISTORY`.
+    pub fn plot(&mut self, ui: &mut egui::Ui, ch: usize, end: usize, width: f32) {
+        let fs = SAMPLING_RATE as f64;
+        let image = self.image(ch, end);
+        let texture = image.map(|(image, first, last)| {
+            let texture = match &mut self.textures[ch] {
+                Some(texture) => {
+                    texture.set(image, TextureOptions::LINEAR);
+                    texture.clone()
+                }
+                None => {
+                    let texture = ui.ctx().load_texture(format!("spectrogram {ch}"), image, TextureOptions::LINEAR);
+                    self.textures[ch] = Some(texture.clone());
+                    texture
+                }
+            };
+            (texture, first as f64 / fs, last as f64 / fs)
+        });
+
+        let (min_freq, max_freq) = (self.min_freq, self.max_freq);
+        let end = end as f64 / fs;
+        Plot::new(format!("Spectrogram {ch}"))
+            .allow_drag(false)
+            .allow_zoom(false)
+            .allow_scroll(false)
+            .show_x(false)
+            .show_axes([false, true])
+            .y_axis_formatter(|mark, _, _| format!("{} Hz", mark.value))
+            .width(width)
+            .show(ui, |plot_ui| {
+                plot_ui.set_plot_bounds(PlotBounds::from_min_max(
+                    [end - SPECTROGRAM_SECS, min_freq],
+                    [end, max_freq],
+                ));
+                if let Some((texture, first, last)) = texture {
+                    let center = PlotPoint::new((first + last) / 2.0, (min_freq + max_freq) / 2.0);
+                    let size = [(last - first) as f32, (max_freq - min_freq) as f32];
+                    plot_ui.image(PlotImage::new(&texture, center, size));
+                }
+            });
+    }
+}
@@ -0,0 +1,227 @@
+//! Band power over time. Once per second the Welch PSDs of `PSD` are
+//! averaged into a PSD that is kept in `TRENDS` for
+//! `TREND_MINUTES`. The band powers are computed from them when drawn or
+//! exported, so the trends follow the bands edited in the settings.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use eframe::egui::{self, DragValue, RichText, Slider};
+use egui_plot::{Legend, Line, Plot, PlotBounds, PlotPoints};
+use rfd::FileDialog;
+
+use super::*;
+use crate::log_err;
+
+/// Minutes of trends kept.
+pub const TREND_MINUTES: f64 = 30.0;
+/// Seconds between the points of the trends.
+pub const TREND_STEP: f64 = 1.0;
+/// Height of the trend plot of each channel.
+const TREND_PLOT_HEIGHT: f32 = 110.0;
+
+/// PSDs of the channels over time.
+pub struct Trends {
+    /// PSD (µV²/Hz) of each channel up to `bands::MAX_FREQ`, oldest first
+    spectra: VecDeque<Vec<Vec<f32>>>,
+    /// Frequency step of the PSDs, in Hz
+    bin_size: f64,
+    /// Index in `HISTORY` of the end of each PSD
+    ends: VecDeque<usize>,
+    /// Sum of the PSDs since the last point
+    sum: Vec<Vec<f32>>,
+    num_summed: usize,
+}
+
+impl Trends {
+    pub(super) fn new() -> Self {
+        Trends {
+            spectra: VecDeque::new(),
+            bin_size: 0.0,
+            ends: VecDeque::new(),
+            sum: vec![],
+            num_summed: 0,
+        }
+    }
+
+    /// Adds the PSDs of the window ending at sample `end` of `HISTORY`. A
+    /// point is appended every `TREND_STEP` with their mean. Changing the
+    /// segment length of the PSD starts the trends over.
+    pub fn push(&mut self, end: usize, psd: &psd::Psd) {
+        if psd.bin_size != self.bin_size {
+            *self = Trends::new();
+            self.bin_size = psd.bin_size;
+        }
+        let num_bins = (bands::MAX_FREQ / psd.bin_size) as usize + 1;
+        if self.sum.is_empty() {
+            self.sum = psd.channels.iter().map(|c| vec![0.0; num_bins.min(c.len())]).collect();
+        }
+        for (sum, values) in self.sum.iter_mut().zip(&psd.channels) {
+            for (s, v) in sum.iter_mut().zip(values) {
+                *s += v;
+            }
+        }
+        self.num_summed += 1;
+
+        let step = (TREND_STEP * SAMPLING_RATE as f64) as usize;
+        let last = self.ends.back().copied().unwrap_or(0);
+        if end < last + step {
+            return;
+        }
+        let n = self.num_summed as f32;
+        let mean = self.sum.iter().map(|sum| sum.iter().map(|s| s / n).collect()).collect();
+        self.spectra.push_back(mean);
+        self.ends.push_back(end);
+        self.sum.clear();
+        self.num_summed = 0;
+
+        let oldest = end.saturating_sub((TREND_MINUTES * 60.0 * SAMPLING_RATE as f64) as usize);
+        while self.ends.front().is_some_and(|e| *e < oldest) {
+            self.ends.pop_front();
+            self.spectra.pop_front();
+        }
+    }
+}
+
+/// Settings of the trend plots.
+pub struct TrendView {
+    /// Minutes shown, from 1 to `TREND_MINUTES`
+    pub minutes: f64,
+    /// Show the power relative to the total power up to `bands::MAX_FREQ`,
+    /// in %, instead of the absolute power in µV²
+    pub relative: bool,
+    /// Seconds of the trailing moving average, 0 for none
+    pub smoothing: f64,
+}
+
+impl Default for TrendView {
+    fn default() -> Self {
+        TrendView { minutes: 5.0, relative: true, smoothing: 10.0 }
+    }
+}
+
+impl TrendView {
+    pub fn controls(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.label("Span: ");
+            ui.add(Slider::new(&mut self.minutes, 1.0..=TREND_MINUTES).suffix(" min"));
+            ui.radio_value(&mut self.relative, true, "relative (%)");
+            ui.radio_value(&mut self.relative, false, "absolute (µV²)");
+            ui.label("Smoothing: ");
+            ui.add(DragValue::new(&mut self.smoothing).clamp_range(0.0..=120.0).speed(1.0).suffix(" s"));
+            if ui.button("Export CSV...").on_hover_text("Band powers of all the kept trends, in µV²").clicked() {
+                if let Some(path) = FileDialog::new().add_filter("CSV", &["csv"]).set_file_name("trends.csv").save_file() {
+                    let bands = BANDS.read().unwrap().clone();
+                    if let Err(e) = export(&path, &bands) {
+                        log_err(format!("Failed to export the trends: {}", e));
+                    }
+                }
+            }
+        });
+    }
+
+    /// Points (minutes before `end`, power) of each band of channel `ch`,
+    /// for the spectra up to sample `end` of `HISTORY`.
+    fn points(&self, ch: usize, end: usize, bands: &[bands::Band]) -> Vec<Vec<[f64; 2]>> {
+        let trends = TRENDS.read().unwrap();
+        let fs = SAMPLING_RATE as f64;
+
+        let shown: Vec<usize> = (0..trends.ends.len())
+            .filter(|i| {
+                let e = trends.ends[*i];
+                e <= end && (end - e) as f64 / fs / 60.0 <= self.minutes
+            })
+            .collect();
+        let times: Vec<f64> = shown.iter().map(|i| -((end - trends.ends[*i]) as f64) / fs / 60.0).collect();
+
+        bands
+            .iter()
+            .map(|band| {
+                let values: Vec<f64> = shown
+                    .iter()
+                    .map(|i| {
+                        let spectrum = &trends.spectra[*i][ch];
+                        if self.relative {
+                            band.share(spectrum, trends.bin_size)
+                        } else {
+                            band.psd_power(spectrum, trends.bin_size)
+                        }
+                    })
+                    .collect();
+                let window = ((self.smoothing / TREND_STEP).round() as usize).max(1);
+                smooth(&values, window).into_iter().zip(&times).map(|(v, t)| [*t, v]).collect()
+            })
+            .collect()
+    }
+
+    /// Draws the trends of every channel, one plot per channel with a line
+    /// per band, for the spectra up to sample `end` of `HISTORY`.
+    pub fn plot(&self, ui: &mut egui::Ui, end: usize) {
+        let bands = BANDS.read().unwrap().clone();
+        let unit = if self.relative { "%" } else { "µV²" };
+        for ch in 0..WAVE_BUFFS_NUM {
+            ui.label(RichText::new(format!("CH-{}", ch + 1)).strong());
+            let lines = self.points(ch, end, &bands);
+            let max = lines.iter().flatten().map(|p| p[1]).fold(0.0, f64::max);
+            let top = if self.relative { 100.0_f64.min(max * 1.1).max(1.0) } else { (max * 1.1).max(1.0) };
+            let minutes = self.minutes;
+            Plot::new(format!("Band trend {ch}"))
+                .height(TREND_PLOT_HEIGHT)
+                .allow_drag(false)
+                .allow_zoom(false)
+                .allow_scroll(false)
+                .legend(Legend::default())
+                .x_axis_formatter(|mark, _, _| format!("{} min", mark.value))
+                .y_axis_formatter(move |mark, _, _| format!("{} {}", mark.value, unit))
+                .show(ui, |plot_ui| {
+                    plot_ui.set_plot_bounds(PlotBounds::from_min_max([-minutes, 0.0], [0.0, top]));
+                    for (band, points) in bands.iter().zip(lines) {
+                        plot_ui.line(Line::new(PlotPoints::new(points)).color(band.color).name(&band.name));
+                    }
+                });
+        }
+    }
+}
+
+/// Writes the band powers (µV²) of every point of `TRENDS` as CSV, one row
+/// per point: its time in seconds, as in the live plots, and a column per
+/// channel and band.
+pub fn export(path: &Path, bands: &[bands::Band]) -> io::Result<()> {
+    let trends = TRENDS.read().unwrap();
+    let mut out = BufWriter::new(File::create(path)?);
+    write!(out, "time")?;
+    for ch in 0..WAVE_BUFFS_NUM {
+        for band in bands {
+            write!(out, ",CH-{} {}", ch + 1, band.name.replace(',', " "))?;
+        }
+    }
+    writeln!(out)?;
+    for (end, spectra) in trends.ends.iter().zip(trends.spectra.iter()) {
+        write!(out, "{}", *end as f64 / SAMPLING_RATE as f64)?;
+        for spectrum in spectra {
+            for band in bands {
+                write!(out, ",{}", band.psd_power(spectrum, trends.bin_size))?;
+            }
+        }
+        writeln!(out)?;
+    }
+    out.flush()
+}
+
+/// Trailing moving average of `window` values.
+fn smooth(values: &[f64], window: usize) -> Vec<f64> {
+    let mut sum = 0.0;
+    values
+        .iter()
+        .enumerate()
+        .map(|(i, v)| {
+            sum += v;
+            if i >= window {
+                sum -= values[i - window];
+            }
+            sum / (i + 1).min(window) as f64
+        })
+        .collect()
+}
@@ -1,7 +1,9 @@
 use crate::{wave::*, log_err};
 use biquad::*;
+use chrono::{DateTime, Local};
 use std::error::Error;
 use std::fmt;
+use std::io;
 use std::io::prelude::*;
 use std::net::{Shutdown, TcpStream, UdpSocket};
 use std::sync::RwLock;
@@ -18,12 +20,60 @@ lazy_static! {
         RwLock::new(buf)
     };
 
+    /// Marks received since the last sample moved to `HISTORY`, with the
+    /// time they arrived.
+    pub static ref PRE_MARKS: RwLock<Vec<(u8, DateTime<Local>)>> = RwLock::new(vec![]);
+
     pub static ref CH_STATUS: RwLock<[bool; WAVE_BUFFS_NUM]> = RwLock::new([true; WAVE_BUFFS_NUM]);
 
     pub static ref ERRORS: RwLock<Vec<String>> = RwLock::new(vec![]);
     pub static ref NOTIFICATIONS: RwLock<Vec<String>> = RwLock::new(vec![]);
+
+    /// Number of packages received from the NAPSE during the last second.
+    pub static ref PACKAGE_RATE: RwLock<usize> = RwLock::new(0);
+}
+
+/// TCP commands of the NAPSE firmware.
+pub const CMD_START: u8 = 0x55;
+pub const CMD_MARK: u8 = 0x33;
+pub const CMD_TEST_ON: u8 = 0x77;
+pub const CMD_NOISE_ON: u8 = 0x66;
+pub const CMD_IMPEDANCE_ON: u8 = 0xdd;
+/// Returns to the normal acquisition mode (test and noise off).
+pub const CMD_MODE_OFF: u8 = 0xaa;
+
+/// Smallest and largest values of the 24-bit ADC of the NAPSE.
+pub const ADC_MIN: i32 = -8388608;
+pub const ADC_MAX: i32 = 8388607;
+
+/// Converts a raw 24-bit ADC sample (two's complement, in the lower 24 bits)
+/// to a float in the -1..1 range.
+pub fn counts_to_float(mut v: i32) -> f32 {
+    if v & 0x800000 != 0 {
+        v |= -16777216i32;
+    }
+
+    let min = ADC_MIN as f64;
+    let max = ADC_MAX as f64;
+
+    let val = v as f64;
+    let v = ((val - min) / (max - min)) as f32;
+    2.0 * v - 1.0
 }
 
+/// Inverse of `counts_to_float`. An `f32` has 24 bits of mantissa, so the
+/// original ADC counts are recovered exactly.
+pub fn float_to_counts(v: f32) -> i32 {
+    let min = ADC_MIN as f64;
+    let max = ADC_MAX as f64;
+    let c = (v as f64 + 1.0) / 2.0 * (max - min) + min;
+    (c.round() as i32).clamp(ADC_MIN, ADC_MAX)
+}
+
+/// Cutoff frequency (Hz) of the Butterworth low-pass filter applied to the
+/// displayed waves. Recordings get the unfiltered samples.
+pub const LOWPASS_CUTOFF: f32 = 40.0;
+
 #[derive(Debug)]
 pub enum NapseError {
     DeviceNotFound,
@@ -45,7 +95,7 @@ pub fn marker_server() {
                     loop {
                         let _ = socket.recv(&mut buf).unwrap();
                         println!("* Received mark from UDP: {}", &buf[0]);
-                        send_tcp_command(0x33, &[buf[0]]).unwrap();
+                        send_tcp_command(CMD_MARK, &[buf[0]]).unwrap();
                     }
                 },
                 Err(e) => {
@@ -63,8 +113,8 @@ pub fn send_tcp_command(cmd: u8, payload: &[u8]) -> Result<(), Box<dyn std::erro
     let pld = payload.to_vec();
     let addr = NAPSE_ADDR.read().unwrap().clone().unwrap();
     let x = std::thread::spawn(move || -> Result<(), std::io::Error> {
-        let mut stream = TcpStream::connect(&format!("{}:1337", addr))?;
-        stream.write(&[&[cmd], pld.as_slice()].concat())?;
+        let mut stream = TcpStream::connect(format!("{}:1337", addr))?;
+        stream.write_all(&[&[cmd], pld.as_slice()].concat())?;
         stream.shutdown(Shutdown::Both)?;
         Ok(())
     });
@@ -79,7 +129,7 @@ fn buffer_sync_loop() {
     let mut val;
 
     // Cutoff and sampling frequencies
-    let f0 = 40.hz();
+    let f0 = LOWPASS_CUTOFF.hz();
     let fs = SAMPLING_RATE.hz();
 
     // Create coefficients for the biquads
@@ -117,6 +167,11 @@ fn buffer_sync_loop() {
                 // Update the pointer
                 // *n = if *n == WAVE_BUFF_LEN - 1 { 0 } else { *n + 1 };
             }
+            let mut history = HISTORY.write().unwrap();
+            history.push(&lasts);
+            for (code, time) in PRE_MARKS.write().unwrap().drain(..) {
+                history.push_mark(code, time);
+            }
         }
         let elapsed_time = now.elapsed();
         if elapsed_time < wait {
@@ -127,7 +182,9 @@ fn buffer_sync_loop() {
     }
 }
 
-pub fn read_napse() -> Result<(), Box<dyn Error>> {
+/// Starts the acquisition once the device address is known and reads its
+/// packages. The impedance measurement mode is only enabled if `impedance`.
+pub fn read_napse(impedance: bool) -> Result<(), Box<dyn Error>> {
     println!("Waiting to press play...");
     loop {
         {
@@ -139,9 +196,11 @@ pub fn read_napse() -> Result<(), Box<dyn Error>> {
         thread::sleep(Duration::from_millis(500));
     }
 
-    send_tcp_command(0x55, &[])?; // send start command
+    send_tcp_command(CMD_START, &[])?; // send start command
 
-    send_tcp_command(0xdd, &[])?; // send impedance ON command
+    if impedance {
+        send_tcp_command(CMD_IMPEDANCE_ON, &[])?; // send impedance ON command
+    }
 
     // start buffer synchronization
     thread::spawn(|| {
@@ -149,16 +208,31 @@ pub fn read_napse() -> Result<(), Box<dyn Error>> {
     });
 
     let socket = UdpSocket::bind("0.0.0.0:31337")?;
+    // wake up every second without packages to report the rate as 0
+    socket.set_read_timeout(Some(Duration::from_secs(1)))?;
 
     let mut buf = [0; 44];
 
     println!("Listening...");
 
     let mut time_start = Instant::now();
-    let mut _n_pkgs = 0;
-    let mut ch_status = vec![false; WAVE_BUFFS_NUM];
+    let mut n_pkgs = 0;
+    let mut ch_status = [false; WAVE_BUFFS_NUM];
+    let mut prev_mark = 0;
     loop {
-        let (_amt, _src) = socket.recv_from(&mut buf)?;
+        // Package counting
+        if time_start.elapsed().as_millis() >= 1000 {
+            *PACKAGE_RATE.write().unwrap() = n_pkgs;
+            time_start = Instant::now();
+            n_pkgs = 0;
+        }
+
+        match socket.recv_from(&mut buf) {
+            Ok(_) => n_pkgs += 1,
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => continue,
+            Err(e) => return Err(Box::new(e)),
+        }
+        let timestamp = chrono::Utc::now().timestamp_micros();
 
         let data: Vec<i32> = buf
             .chunks(4)
@@ -166,78 +240,51 @@ pub fn read_napse() -> Result<(), Box<dyn Error>> {
             .map(|v| i32::from_le_bytes([v[0], v[1], v[2], v[3]]))
             .collect();
 
-        let to_float = |mut v: i32| {
-            if v & 0x800000 != 0 {
-                v |= -16777216i32;
-            }
-
-            let min = -8388608.0_f64;
-            let max = 8388607.0_f64;
-
-            let val = v as f64;
-            let v = ((val - min) / (max - min)) as f32;
-            2.0 * v - 1.0
-        };
-
         if data.len() != 9 {
             return Err(Box::new(NapseError::FailedToReadAllChannels));
         }
 
         // get channel status
         let status_data = u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]);
-        for i in 0..WAVE_BUFFS_NUM {
-            let not_stat = (status_data & (1 << 12 + i)) >> (12 + i);
-            ch_status[i] = if not_stat == 1 { false } else { true} ;
-        }
-        {
-            let mut status_array = CH_STATUS.write().unwrap();
-            for i in 0..WAVE_BUFFS_NUM {
-                status_array[i] = ch_status[i];
-            }
+        for (i, status) in ch_status.iter_mut().enumerate() {
+            let not_stat = (status_data & (1 << (12 + i))) >> (12 + i);
+            *status = not_stat != 1;
         }
+        *CH_STATUS.write().unwrap() = ch_status;
 
-        let mut channel_data = vec![];
-        for i in 0..WAVE_BUFFS_NUM {
-            channel_data.push(to_float(data[i]));
-        }
+        let channel_data: Vec<f32> = data.iter().take(WAVE_BUFFS_NUM).map(|v| counts_to_float(*v)).collect();
 
         // Write the readed data to the wave buffers
         {
             let mut buffs = PRE_BUFFS.write()?;
-            for (buf_idx, buffer) in buffs.iter_mut().enumerate() {
-                let val = channel_data[buf_idx];
-                buffer.push(val);
-
-                // Wave recording
-                {
-                    let record_flag = RECORDING_FLAG.read().unwrap();
-                    if *record_flag {
-                        let mut rec_buf = RECORDING_BUFFS.write().unwrap();
-                        rec_buf[buf_idx].push(val);
-                        rec_buf[WAVE_BUFFS_NUM + buf_idx].push(if ch_status[buf_idx] { 1.0 } else { 0.0 });
-
-                        if buf_idx == WAVE_BUFFS_NUM - 1 {
-                            let mark = buf[40];
-                            let n = rec_buf.len()-1;
-                            rec_buf[n].push(mark as f32);
-                        }
-                    }
-                }
+            for (buffer, val) in buffs.iter_mut().zip(&channel_data) {
+                buffer.push(*val);
             }
         }
 
+        // A mark can span several packages, only the first one is shown
+        let mark = buf[40];
+        if mark != 0 && mark != prev_mark {
+            PRE_MARKS.write().unwrap().push((mark, chrono::Local::now()));
+        }
+        prev_mark = mark;
+
+        // Wave recording. All the columns of the sample are pushed holding
+        // the lock, so that the recorder never sees a partial sample.
+        if *RECORDING_FLAG.read().unwrap() {
+            let mut rec_buf = RECORDING_BUFFS.write().unwrap();
+            for (buf_idx, val) in channel_data.iter().enumerate() {
+                rec_buf[buf_idx].push(*val);
+                rec_buf[WAVE_BUFFS_NUM + buf_idx].push(if ch_status[buf_idx] { 1.0 } else { 0.0 });
+            }
+            rec_buf[2 * WAVE_BUFFS_NUM].push(mark as f32);
+            RECORDING_TIMES.write().unwrap().push(timestamp);
+        }
+
         // Clear buffer
         for elem in buf.iter_mut() {
             *elem = 0;
         }
-
-        // Package counting
-        _n_pkgs += 1;
-        if time_start.elapsed().as_millis() >= 1000 {
-            // println!("*** num packages: {}", n_pkgs);
-            time_start = Instant::now();
-            _n_pkgs = 0;
-        }
     }
 }
 